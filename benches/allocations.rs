@@ -0,0 +1,162 @@
+//! Heap allocations and time spent per request by the sync service
+//!
+//! Allocations are counted by a wrapping global allocator and reported through a criterion
+//! measurement, so the `allocations` groups show allocations per request instead of time.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use criterion::{
+    criterion_group, criterion_main,
+    measurement::{Measurement, ValueFormatter, WallTime},
+    BatchSize, Criterion, Throughput,
+};
+use iotscape::{socket::MockSocket, *};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Criterion measurement counting allocations and reallocations
+struct Allocations;
+
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(&self, _typical: f64, throughput: &Throughput, values: &mut [f64]) -> &'static str {
+        if let Throughput::Elements(n) = throughput {
+            values.iter_mut().for_each(|v| *v /= *n as f64);
+        }
+        "allocs/req"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+const REQUESTS: u64 = 100;
+
+fn definition() -> ServiceDefinition {
+    ServiceDefinition::new("bench", IoTScapeServiceDescription::default()).with_method(
+        "add",
+        MethodDescription::new("Adds two numbers")
+            .with_param("a", IoTScapeType::Number, "First number")
+            .with_param("b", IoTScapeType::Number, "Second number")
+            .with_return(IoTScapeType::Number),
+    )
+}
+
+fn service() -> IoTScapeService<MockSocket> {
+    IoTScapeService::new("Bench", definition(), "10.0.0.1:1978".parse().unwrap())
+}
+
+/// Datagrams for a batch of requests, built outside of the measurement
+fn requests(function: &str) -> Vec<(std::net::SocketAddr, Vec<u8>)> {
+    (0..REQUESTS)
+        .map(|id| {
+            let request = format!(
+                r#"{{"id":"{}","service":"Bench","device":"bench","function":"{}","params":[1,2],"clientId":"client"}}"#,
+                id, function
+            );
+            ("10.0.0.1:1978".parse().unwrap(), request.into_bytes())
+        })
+        .collect()
+}
+
+/// Receive a batch of requests and answer each of them
+fn handle_requests(service: &mut IoTScapeService<MockSocket>, requests: Vec<(std::net::SocketAddr, Vec<u8>)>) {
+    service.socket().sent.borrow_mut().clear();
+    service.socket().data.borrow_mut().extend(requests);
+    service.poll(None);
+    while let Some(request) = service.rx_queue.pop_front() {
+        service.respond(request, Ok(vec![3.into()])).unwrap();
+    }
+}
+
+fn service_benches<M: Measurement>(c: &mut Criterion<M>, group: &str) {
+    let mut group = c.benchmark_group(group);
+    group.throughput(Throughput::Elements(REQUESTS));
+
+    let mut calls = service();
+    group.bench_function("calls", |b| {
+        b.iter_batched(|| requests("add"), |requests| handle_requests(&mut calls, requests), BatchSize::PerIteration)
+    });
+
+    let mut heartbeats = service();
+    group.bench_function("heartbeats", |b| {
+        b.iter_batched(|| requests("heartbeat"), |requests| handle_requests(&mut heartbeats, requests), BatchSize::PerIteration)
+    });
+
+    group.finish();
+}
+
+fn allocations(c: &mut Criterion<Allocations>) {
+    service_benches(c, "allocations");
+}
+
+fn time(c: &mut Criterion<WallTime>) {
+    service_benches(c, "time");
+}
+
+criterion_group! {
+    name = allocation_benches;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations
+}
+criterion_group!(time_benches, time);
+criterion_main!(allocation_benches, time_benches);
@@ -0,0 +1,205 @@
+//! Throughput and latency of the async service with handlers on other threads
+//!
+//! Requests are sent over localhost UDP with a bounded number in flight, handled by one spawned
+//! task each, and answered either through the response channel or through `queue_response` and `poll`.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crossbeam_queue::SegQueue;
+use futures::{SinkExt, StreamExt};
+use iotscape::*;
+use tokio::{net::UdpSocket, runtime::Runtime};
+
+const BATCH: u64 = 1_000;
+const IN_FLIGHT: u64 = 32;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Bench {
+    server: UdpSocket,
+    device: SocketAddr,
+}
+
+fn definition() -> ServiceDefinition {
+    ServiceDefinition::new("bench", IoTScapeServiceDescription::default()).with_method(
+        "add",
+        MethodDescription::new("Adds two numbers")
+            .with_param("a", IoTScapeType::Number, "First number")
+            .with_param("b", IoTScapeType::Number, "Second number")
+            .with_return(IoTScapeType::Number),
+    )
+}
+
+fn sum(request: &Request) -> Vec<Value> {
+    vec![request.params.iter().filter_map(|p| p.as_f64()).sum::<f64>().into()]
+}
+
+async fn service(server: &UdpSocket) -> (Arc<IoTScapeServiceAsync>, SocketAddr) {
+    let service: Arc<IoTScapeServiceAsync> =
+        Arc::new(IoTScapeServiceAsync::new("Bench", definition(), server.local_addr().unwrap()).await);
+    let device = SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+    (service, device)
+}
+
+/// Handlers answer through the response channel while `next_request` waits for datagrams
+async fn setup_channel() -> Bench {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (service, device) = service(&server).await;
+
+    tokio::spawn(async move {
+        let mut requests = service.requests();
+        while let Some(request) = requests.next().await {
+            let service = service.clone();
+            tokio::spawn(async move {
+                let response = service.response_to(&request, Ok(sum(&request)));
+                service.responses().send(response).await.unwrap();
+            });
+        }
+    });
+
+    Bench { server, device }
+}
+
+/// Handlers take requests with `pop_request` and queue responses with `queue_response` while
+/// another task polls
+async fn setup_polled() -> Bench {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (service, device) = service(&server).await;
+
+    let poller = service.clone();
+    tokio::spawn(async move {
+        loop {
+            poller.poll().await;
+            tokio::task::yield_now().await;
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            while let Some(request) = service.pop_request() {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    service.queue_response(service.response_to(&request, Ok(sum(&request))));
+                });
+            }
+            tokio::task::yield_now().await;
+        }
+    });
+
+    Bench { server, device }
+}
+
+async fn send_request(bench: &Bench) {
+    let request = format!(
+        r#"{{"id":"{}","service":"Bench","device":"bench","function":"add","params":[1,2]}}"#,
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    bench.server.send_to(request.as_bytes(), bench.device).await.unwrap();
+}
+
+async fn receive_response(bench: &Bench, buf: &mut [u8]) {
+    tokio::time::timeout(Duration::from_secs(5), bench.server.recv_from(buf))
+        .await
+        .expect("response lost")
+        .unwrap();
+}
+
+/// Send a batch of requests keeping a few in flight, waiting for every response
+async fn run_batch(bench: &Bench) {
+    let mut buf = [0u8; 65_535];
+    let mut sent = 0;
+
+    while sent < IN_FLIGHT.min(BATCH) {
+        send_request(bench).await;
+        sent += 1;
+    }
+    for _ in 0..BATCH {
+        receive_response(bench, &mut buf).await;
+        if sent < BATCH {
+            send_request(bench).await;
+            sent += 1;
+        }
+    }
+}
+
+async fn round_trip(bench: &Bench) {
+    let mut buf = [0u8; 65_535];
+    send_request(bench).await;
+    receive_response(bench, &mut buf).await;
+}
+
+fn service_benches(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let setups = [
+        ("channel", runtime.block_on(setup_channel())),
+        ("polled", runtime.block_on(setup_polled())),
+    ];
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(BATCH));
+    for (name, bench) in &setups {
+        group.bench_with_input(BenchmarkId::from_parameter(name), bench, |b, bench| {
+            b.iter(|| runtime.block_on(run_batch(bench)));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("latency");
+    for (name, bench) in &setups {
+        group.bench_with_input(BenchmarkId::from_parameter(name), bench, |b, bench| {
+            b.iter(|| runtime.block_on(round_trip(bench)));
+        });
+    }
+    group.finish();
+}
+
+/// Handler threads pushing while one thread drains, as the poller does with queued responses
+fn contended<Q: Sync>(queue: &Q, push: impl Fn(&Q, u64) + Sync, pop: impl Fn(&Q) -> Option<u64>) {
+    const PRODUCERS: u64 = 4;
+    const PER_PRODUCER: u64 = 2_500;
+
+    thread::scope(|s| {
+        for _ in 0..PRODUCERS {
+            s.spawn(|| (0..PER_PRODUCER).for_each(|i| push(queue, i)));
+        }
+
+        let mut received = 0;
+        while received < PRODUCERS * PER_PRODUCER {
+            match pop(queue) {
+                Some(_) => received += 1,
+                None => std::hint::spin_loop(),
+            }
+        }
+    });
+}
+
+fn queue_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended_queue");
+    group.throughput(Throughput::Elements(10_000));
+    group.bench_function("mutex_vecdeque", |b| {
+        b.iter(|| {
+            let queue = Mutex::new(VecDeque::new());
+            contended(&queue, |q, i| q.lock().unwrap().push_back(i), |q| q.lock().unwrap().pop_front());
+        })
+    });
+    group.bench_function("segqueue", |b| {
+        b.iter(|| {
+            let queue = SegQueue::new();
+            contended(&queue, |q, i| q.push(i), |q| q.pop());
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, service_benches, queue_benches);
+criterion_main!(benches);
@@ -1,20 +1,17 @@
 use std::{
     collections::BTreeMap,
-    sync::{Arc, LazyLock, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
     vec,
 };
 
 use std::str::FromStr;
 
-use iotscape::*;
 
-//static SERVER: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_SERVER").unwrap_or("52.73.65.98:1978".to_string()));
-static SERVER: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_SERVER").unwrap_or("127.0.0.1:1978".to_string()));
-//static ANNOUNCE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_ANNOUNCE_ENDPOINT").unwrap_or("https://services.netsblox.org/routes/iotscape/announce".to_string()));
-static ANNOUNCE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_ANNOUNCE_ENDPOINT").unwrap_or("http://localhost:8080/routes/iotscape/announce".to_string()));
-// static RESPONSE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_RESPONSE_ENDPOINT").unwrap_or("http://services.netsblox.org/routes/iotscape/response".to_string()));
-static RESPONSE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_RESPONSE_ENDPOINT").unwrap_or("http://localhost:8080/routes/iotscape/response".to_string()));
+use iotscape::{config::IoTScapeConfig, *};
 
 #[tokio::main]
 async fn main() {
@@ -22,94 +19,59 @@ async fn main() {
     simple_logger::init_with_level(log::Level::Info).unwrap();
 
     // Create definition struct
-    let mut definition = ServiceDefinition {
-        id: "rs1".to_owned(),
-        methods: BTreeMap::new(),
-        events: BTreeMap::new(),
-        description: IoTScapeServiceDescription {
+    let definition = ServiceDefinition::new(
+        "rs1",
+        IoTScapeServiceDescription {
             description: Some("Test IoTScape service.".to_owned()),
-            externalDocumentation: None,
-            termsOfService: None,
             contact: Some("gstein@ltu.edu".to_owned()),
-            license: None,
             version: "1".to_owned(),
+            ..Default::default()
         },
-    };
+    )
+    .with_method(
+        "helloWorld",
+        MethodDescription::new("Says \"Hello, World!\"")
+            .with_return(IoTScapeType::String)
+            .with_return_documentation("The text \"Hello, World!\""),
+    )
+    .with_method(
+        "add",
+        MethodDescription::new("Adds two numbers")
+            .with_param("a", IoTScapeType::Number, "First number")
+            .with_param("b", IoTScapeType::Number, "Second number")
+            .with_return(IoTScapeType::Number)
+            .with_return_documentation("The sum of a and b"),
+    )
+    .with_method(
+        "timer",
+        MethodDescription::new("Sends timer event on a delay")
+            .with_param("msec", IoTScapeType::Number, "Amount of time to wait, in ms")
+            .with_return(IoTScapeType::Event("timer".to_owned()))
+            .with_return_documentation("Response after delay"),
+    )
+    .with_method(
+        "returnComplex",
+        MethodDescription::new("Complex response to method")
+            .with_return(IoTScapeType::String)
+            .with_return(IoTScapeType::String)
+            .with_return_documentation("Complex object"),
+    )
+    .with_event("timer", &[]);
 
-    // Define methods
-    definition.methods.insert(
-        "helloWorld".to_owned(),
-        MethodDescription {
-            documentation: Some("Says \"Hello, World!\"".to_owned()),
-            params: vec![],
-            returns: MethodReturns {
-                documentation: Some("The text \"Hello, World!\"".to_owned()),
-                r#type: vec!["string".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "add".to_owned(),
-        MethodDescription {
-            documentation: Some("Adds two numbers".to_owned()),
-            params: vec![
-                MethodParam {
-                    name: "a".to_owned(),
-                    documentation: Some("First number".to_owned()),
-                    r#type: "number".to_owned(),
-                    optional: false,
-                },
-                MethodParam {
-                    name: "b".to_owned(),
-                    documentation: Some("Second number".to_owned()),
-                    r#type: "number".to_owned(),
-                    optional: false,
-                },
-            ],
-            returns: MethodReturns {
-                documentation: Some("The sum of a and b".to_owned()),
-                r#type: vec!["number".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "timer".to_owned(),
-        MethodDescription {
-            documentation: Some("Sends timer event on a delay".to_owned()),
-            params: vec![MethodParam {
-                name: "msec".to_owned(),
-                documentation: Some("Amount of time to wait, in ms".to_owned()),
-                r#type: "number".to_owned(),
-                optional: false,
-            }],
-            returns: MethodReturns {
-                documentation: Some("Response after delay".to_owned()),
-                r#type: vec!["event timer".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "returnComplex".to_owned(),
-        MethodDescription {
-            documentation: Some("Complex response to method".to_owned()),
-            params: vec![],
-            returns: MethodReturns {
-                documentation: Some("Complex object".to_owned()),
-                r#type: vec!["string".to_owned(), "string".to_owned()],
-            },
-        },
-    );
+    // Local server unless changed, set IOTSCAPE_PRESET=netsblox for the public one
+    let config = IoTScapeConfig::local().with_env().expect("Invalid IoTScape config");
 
-    definition.events.insert(
-        "timer".to_owned(),
-        EventDescription { params: vec![] },
-    );
-
-    let service: Arc<Mutex<IoTScapeService>> = Arc::from(Mutex::new(IoTScapeService::new(
+    // Responses too large for UDP, like images, go through HTTP to the config's response URL
+    let mut service = IoTScapeService::from_config(
         "ExampleService",
         definition,
-        SERVER.parse().unwrap(),
-    )));
+        &config,
+    ).expect("Could not find server");
+
+    // Reject calls that don't match the definition before they reach the handlers
+    service.validate_requests = true;
+
+    let service: Arc<Mutex<IoTScapeService>> = Arc::from(Mutex::new(service));
 
     if let Err(e) = service
         .lock()
@@ -123,9 +85,13 @@ async fn main() {
 
     let service_clone = Arc::clone(&service);
 
-    tokio::task::spawn(async move {
+    // Cleared to stop polling, so the service can be taken back and shut down
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = Arc::clone(&running);
+
+    let poll_task = tokio::task::spawn(async move {
         let service = service_clone;
-        loop {
+        while running_clone.load(Ordering::Relaxed) {
             tokio::time::sleep(Duration::from_millis(1)).await;
             service.lock().unwrap().poll(Some(Duration::from_millis(1)));
 
@@ -144,7 +110,7 @@ async fn main() {
 
             // Handle requests
             loop {
-                if service.lock().unwrap().rx_queue.len() == 0 {
+                if service.lock().unwrap().rx_queue.is_empty() {
                     break;
                 }
 
@@ -158,7 +124,7 @@ async fn main() {
                         service
                             .lock()
                             .unwrap()
-                            .enqueue_response_to(next_msg, Ok(vec!["Hello, World!".to_owned().into()])).unwrap();
+                            .respond(next_msg, Ok(vec!["Hello, World!".to_owned().into()])).unwrap();
                     },
                     "add" => {
                         let result: f64 = next_msg
@@ -167,7 +133,7 @@ async fn main() {
                             .map(|v| 
                                 match v {
                                     serde_json::Value::Number(n) => n.as_f64().unwrap_or_default(),
-                                    serde_json::Value::String(s) => f64::from_str(&s).unwrap_or_default(),
+                                    serde_json::Value::String(s) => f64::from_str(s).unwrap_or_default(),
                                     _ => 0.0,
                                 })
                             .sum();
@@ -176,13 +142,13 @@ async fn main() {
                             service
                                 .lock()
                                 .unwrap()
-                                .enqueue_response_to_http(&RESPONSE_ENDPOINT, next_msg, Ok(vec![result.to_string().into()])).unwrap();
+                                .enqueue_response_to_http(next_msg, Ok(vec![result.to_string().into()])).unwrap();
                         });
                     },
                     "timer" => {
                         let ms = next_msg
                             .params
-                            .get(0).and_then(|x| u64::from_str_radix(&x.to_string(), 10).ok())
+                            .first().and_then(|x| x.to_string().parse::<u64>().ok())
                             .unwrap_or(0);
                         tokio::spawn(delayed_event(
                             Arc::clone(&service),
@@ -194,16 +160,16 @@ async fn main() {
                         service
                             .lock()
                             .unwrap()
-                            .enqueue_response_to(next_msg, Ok(vec![])).unwrap();      
+                            .respond(next_msg, Ok(vec![])).unwrap();      
                     },
                     "returnComplex" => {
                         // Load image
                         let image = std::fs::read("examples/figure.png").expect("Could not read image file");
-                        let image = "<costume  name=\"costume\" collabId=\"\" center-x=\"43.5\" center-y=\"62\" image=\"data:image/png;base64,".to_string() + base64::encode(&image).as_str() + "\"/>";
+                        let image = Costume::from_png(image).with_center(43.5, 62.0);
                         let service: Arc<Mutex<IoTScapeService>> = service.clone();
                         tokio::task::spawn_blocking(move || {
                             service.lock().unwrap()
-                               .enqueue_response_to_http(&RESPONSE_ENDPOINT, next_msg, Ok(vec![vec![Into::<serde_json::Value>::into("test"), vec![1, 2, 3].into(), vec![image].into()].into()])).expect("Could not enqueue response");
+                               .respond(next_msg, Ok(("test", vec![1, 2, 3], vec![image]).into_response())).expect("Could not enqueue response");
                         });
                     },
                     "_requestedKey" => {
@@ -211,7 +177,7 @@ async fn main() {
                         service
                             .lock()
                             .unwrap()
-                            .enqueue_response_to(next_msg, Ok(vec![])).unwrap();      
+                            .respond(next_msg, Ok(vec![])).unwrap();      
                     },
                     t => {
                         println!("Unrecognized function {}", t);
@@ -239,36 +205,45 @@ async fn main() {
             },
             "announcehttp" => {
                 let service = Arc::clone(&service);
-                let announce_endpoint = ANNOUNCE_ENDPOINT.clone();
                 tokio::task::spawn_blocking(move || {
-                    service.lock().unwrap().announce_http(&announce_endpoint).expect("Could not announce to server");
+                    service.lock().unwrap().announce_http().expect("Could not announce to server");
                 }).await.expect("Could not spawn blocking task");
             },
             "announcelite" => {
                 service.lock().unwrap().announce_lite().expect("Could not announce to server");
             },
+            #[cfg(feature = "security")]
             "getkey" => {
-                let mut s = service.lock().unwrap();
-                let next_msg_id = s.next_msg_id.to_string();
-                s.send_event(&next_msg_id, "_requestKey", BTreeMap::default()).unwrap();
-                s.next_msg_id += 1;                
+                service.lock().unwrap().request_key().expect("Could not request a key");
             },
+            #[cfg(feature = "security")]
             "reset" => {
-                let mut s = service.lock().unwrap();
-                let next_msg_id = s.next_msg_id.to_string();
-                s.send_event(&next_msg_id, "_reset", BTreeMap::default()).unwrap();
-                s.next_msg_id += 1;
+                service.lock().unwrap().reset_key().expect("Could not reset the key");
             },
             "help" => {
                 println!("Commands:");
                 println!("  announce - send a new announce to the server");
                 println!("  announcehttp - send a new announce to the server via HTTP");
                 println!("  announcelite - send a new announce to the server with minimal information");
+                #[cfg(feature = "security")]
                 println!("  getkey - request a key from the server");
+                #[cfg(feature = "security")]
                 println!("  reset - reset the encryption settings on the server");
                 println!("  quit - exit the program");
             },
             "quit" => {
+                running.store(false, Ordering::Relaxed);
+                let _ = poll_task.await;
+
+                // Tasks still handling requests keep their own reference
+                match Arc::into_inner(service) {
+                    Some(service) => {
+                        if let Err(e) = service.into_inner().unwrap().shutdown(Duration::from_secs(1), true) {
+                            println!("Could not shut down cleanly: {}", e);
+                        }
+                    }
+                    None => println!("Requests are still being handled, exiting without shutting down"),
+                }
                 break;
             },
             _ => {
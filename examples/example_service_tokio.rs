@@ -1,206 +1,161 @@
-use std::sync::LazyLock;
 #[cfg(feature = "tokio")]
 use std::{
     collections::BTreeMap,
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
     vec,
 };
 #[cfg(feature = "tokio")]
 use std::str::FromStr;
 
 #[cfg(feature = "tokio")]
-use iotscape::*;
+use iotscape::{config::IoTScapeConfig, *};
 #[cfg(feature = "tokio")]
 use log::info;
 #[cfg(feature = "tokio")]
 use tokio::spawn;
 
-//static SERVER: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_SERVER").unwrap_or("52.73.65.98:1978".to_string()));
-static SERVER: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_SERVER").unwrap_or("127.0.0.1:1978".to_string()));
-//static ANNOUNCE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_ANNOUNCE_ENDPOINT").unwrap_or("https://services.netsblox.org/routes/iotscape/announce".to_string()));
-static ANNOUNCE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_ANNOUNCE_ENDPOINT").unwrap_or("http://localhost:8080/routes/iotscape/announce".to_string()));
-// static RESPONSE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_RESPONSE_ENDPOINT").unwrap_or("http://services.netsblox.org/routes/iotscape/response".to_string()));
-static RESPONSE_ENDPOINT: LazyLock<String> = LazyLock::new(|| std::env::var("IOTSCAPE_RESPONSE_ENDPOINT").unwrap_or("http://localhost:8080/routes/iotscape/response".to_string()));
-
 #[cfg(feature = "tokio")]
 #[tokio::main]
 async fn main() {
     // Create definition struct
 
-    let mut definition = ServiceDefinition {
-        id: "rs1".to_owned(),
-        methods: BTreeMap::new(),
-        events: BTreeMap::new(),
-        description: IoTScapeServiceDescription {
+    let definition = ServiceDefinition::new(
+        "rs1",
+        IoTScapeServiceDescription {
             description: Some("Test IoTScape service.".to_owned()),
-            externalDocumentation: None,
-            termsOfService: None,
             contact: Some("gstein@ltu.edu".to_owned()),
-            license: None,
             version: "1".to_owned(),
+            ..Default::default()
         },
-    };
-
-    // Define methods
-    definition.methods.insert(
-        "helloWorld".to_owned(),
-        MethodDescription {
-            documentation: Some("Says \"Hello, World!\"".to_owned()),
-            params: vec![],
-            returns: MethodReturns {
-                documentation: Some("The text \"Hello, World!\"".to_owned()),
-                r#type: vec!["string".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "add".to_owned(),
-        MethodDescription {
-            documentation: Some("Adds two numbers".to_owned()),
-            params: vec![
-                MethodParam {
-                    name: "a".to_owned(),
-                    documentation: Some("First number".to_owned()),
-                    r#type: "number".to_owned(),
-                    optional: false,
-                },
-                MethodParam {
-                    name: "b".to_owned(),
-                    documentation: Some("Second number".to_owned()),
-                    r#type: "number".to_owned(),
-                    optional: false,
-                },
-            ],
-            returns: MethodReturns {
-                documentation: Some("The sum of a and b".to_owned()),
-                r#type: vec!["number".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "timer".to_owned(),
-        MethodDescription {
-            documentation: Some("Sends timer event on a delay".to_owned()),
-            params: vec![MethodParam {
-                name: "msec".to_owned(),
-                documentation: Some("Amount of time to wait, in ms".to_owned()),
-                r#type: "number".to_owned(),
-                optional: false,
-            }],
-            returns: MethodReturns {
-                documentation: Some("Response after delay".to_owned()),
-                r#type: vec!["event timer".to_owned()],
-            },
-        },
-    );
-    definition.methods.insert(
-        "returnComplex".to_owned(),
-        MethodDescription {
-            documentation: Some("Complex response to method".to_owned()),
-            params: vec![],
-            returns: MethodReturns {
-                documentation: Some("Complex object".to_owned()),
-                r#type: vec!["string".to_owned(), "string".to_owned()],
-            },
-        },
-    );
-
-    definition.events.insert(
-        "timer".to_owned(),
-        EventDescription { params: vec![] },
-    );
-
-    let service: Arc<IoTScapeServiceAsync> = Arc::from(IoTScapeServiceAsync::new(
+    )
+    .with_method(
+        "helloWorld",
+        MethodDescription::new("Says \"Hello, World!\"")
+            .with_return(IoTScapeType::String)
+            .with_return_documentation("The text \"Hello, World!\""),
+    )
+    .with_method(
+        "add",
+        MethodDescription::new("Adds two numbers")
+            .with_param("a", IoTScapeType::Number, "First number")
+            .with_param("b", IoTScapeType::Number, "Second number")
+            .with_return(IoTScapeType::Number)
+            .with_return_documentation("The sum of a and b"),
+    )
+    .with_method(
+        "timer",
+        MethodDescription::new("Sends timer event on a delay")
+            .with_param("msec", IoTScapeType::Number, "Amount of time to wait, in ms")
+            .with_return(IoTScapeType::Event("timer".to_owned()))
+            .with_return_documentation("Response after delay"),
+    )
+    .with_method(
+        "returnComplex",
+        MethodDescription::new("Complex response to method")
+            .with_return(IoTScapeType::String)
+            .with_return(IoTScapeType::String)
+            .with_return_documentation("Complex object"),
+    )
+    .with_event("timer", &[]);
+
+    // Local server unless changed, set IOTSCAPE_PRESET=netsblox for the public one
+    let config = IoTScapeConfig::local().with_env().expect("Invalid IoTScape config");
+
+    // Responses too large for UDP, like images, go through HTTP to the config's response URL
+    let mut service: IoTScapeServiceAsync = IoTScapeServiceAsync::from_config(
         "ExampleService",
         definition,
-        SERVER.parse().unwrap(),
-    ).await);
+        &config,
+    ).await.expect("Could not find server");
+
+    // Reject calls that don't match the definition before they reach the handlers
+    service.validate_requests = true;
+
+    let service = Arc::new(service);
 
     service
         .announce()
         .await
         .expect("Could not announce to server");
 
-    let mut last_announce = Instant::now();
-    let announce_period = Duration::from_secs(30);
-
+    // Re-announce to server regularly
     let service_clone = service.clone();
-
     tokio::task::spawn(async move {
-        let service = service_clone;
+        let mut announce_interval = tokio::time::interval(Duration::from_secs(30));
+        announce_interval.tick().await;
         loop {
-            tokio::time::sleep(Duration::from_millis(10)).await;
-            service.poll().await;
+            announce_interval.tick().await;
+            service_clone
+                .announce()
+                .await
+                .expect("Could not announce to server");
+        }
+    });
 
-            // Re-announce to server regularly
-            if last_announce.elapsed() > announce_period {
-                service
-                    .announce()
-                    .await
-                    .expect("Could not announce to server");
-                last_announce = Instant::now();
-            }
+    let service_clone = service.clone();
 
-            // Handle requests
-            while let Some(next_msg) = service.rx_queue.lock().unwrap().pop_front() {
-                println!("Handling message {:?}", next_msg);
+    tokio::task::spawn(async move {
+        let service = service_clone;
 
-                let service = service.clone();
-                spawn(async move { 
-                    // Request handlers
-                    match next_msg.function.as_str() {
-                        "helloWorld" => {
-                                service.enqueue_response_to(next_msg, Ok(vec!["Hello, World!".to_owned().into()])).await.expect("Could not enqueue response");
-                        },
-                        "add" => {
-                            let result: f64 = next_msg
-                                .params
-                                .iter()
-                                .map(|v| 
-                                    match v {
-                                        serde_json::Value::Number(n) => n.as_f64().unwrap_or_default(),
-                                        serde_json::Value::String(s) => f64::from_str(&s).unwrap_or_default(),
-                                        _ => 0.0,
-                                    })
-                                .sum(); 
-                                service
-                                    .enqueue_response_to_http(&RESPONSE_ENDPOINT, next_msg, Ok(vec![result.to_string().into()])).await.expect("Could not enqueue response");
-                        },
-                        "timer" => {
-                            info!("Received timer request {:?}", next_msg);
-                            let ms = next_msg
-                                .params
-                                .get(0).and_then(|x| u64::from_str_radix(&x.to_string(), 10).ok())
-                                .unwrap_or(0);
-                            spawn(delayed_event(
-                                service.clone(),
-                                ms,
-                                next_msg.id.clone(),
-                                "timer",
-                                BTreeMap::new(),
-                            ));
+        // Handle requests as they arrive
+        while let Some(next_msg) = service.next_request().await {
+            println!("Handling message {:?}", next_msg);
+
+            let service = service.clone();
+            spawn(async move { 
+                // Request handlers
+                match next_msg.function.as_str() {
+                    "helloWorld" => {
+                            service.respond(next_msg, Ok(vec!["Hello, World!".to_owned().into()])).await.expect("Could not enqueue response");
+                    },
+                    "add" => {
+                        let result: f64 = next_msg
+                            .params
+                            .iter()
+                            .map(|v| 
+                                match v {
+                                    serde_json::Value::Number(n) => n.as_f64().unwrap_or_default(),
+                                    serde_json::Value::String(s) => f64::from_str(s).unwrap_or_default(),
+                                    _ => 0.0,
+                                })
+                            .sum(); 
                             service
-                                .enqueue_response_to(next_msg, Ok(vec![])).await.expect("Could not enqueue response");    
-                        },
-                        "returnComplex" => {
-                            // Load image
-                            let image = std::fs::read("examples/figure.png").expect("Could not read image file");
-                            let image = "<costume  name=\"costume\" collabId=\"\" center-x=\"43.5\" center-y=\"62\" image=\"data:image/png;base64,".to_string() + base64::encode(&image).as_str() + "\"/>";
-                            service
-                                .enqueue_response_to_http(&RESPONSE_ENDPOINT, next_msg, Ok(vec![vec![Into::<serde_json::Value>::into("test"), vec![1, 2, 3].into(), vec![image].into()].into()])).await.expect("Could not enqueue response");
-                        },
-                        "_requestedKey" => {
-                            println!("Received key: {:?}", next_msg.params);
-                            service
-                                .enqueue_response_to(next_msg, Ok(vec![])).await.expect("Could not enqueue response");      
-                        },
-                        t => {
-                            println!("Unrecognized function {}", t);
-                        }
+                                .enqueue_response_to_http(next_msg, Ok(vec![result.to_string().into()])).await.expect("Could not enqueue response");
+                    },
+                    "timer" => {
+                        info!("Received timer request {:?}", next_msg);
+                        let ms = next_msg
+                            .params
+                            .first().and_then(|x| x.to_string().parse::<u64>().ok())
+                            .unwrap_or(0);
+                        spawn(delayed_event(
+                            service.clone(),
+                            ms,
+                            next_msg.id.clone(),
+                            "timer",
+                            BTreeMap::new(),
+                        ));
+                        service
+                            .respond(next_msg, Ok(vec![])).await.expect("Could not enqueue response");    
+                    },
+                    "returnComplex" => {
+                        // Load image
+                        let image = std::fs::read("examples/figure.png").expect("Could not read image file");
+                        let image = Costume::from_png(image).with_center(43.5, 62.0);
+                        service
+                            .respond(next_msg, Ok(("test", vec![1, 2, 3], vec![image]).into_response())).await.expect("Could not enqueue response");
+                    },
+                    "_requestedKey" => {
+                        println!("Received key: {:?}", next_msg.params);
+                        service
+                            .respond(next_msg, Ok(vec![])).await.expect("Could not enqueue response");      
+                    },
+                    t => {
+                        println!("Unrecognized function {}", t);
                     }
-                });
-            }
+                }
+            });
         }
     });
 
@@ -217,29 +172,34 @@ async fn main() {
         let _args = parts.collect::<Vec<&str>>();
         
         match command {
+            #[cfg(feature = "security")]
             "getkey" => {
-                let next_msg_id = service.next_msg_id.load(std::sync::atomic::Ordering::Relaxed).to_string();
-                service.send_event(&next_msg_id, "_requestKey", BTreeMap::default()).await.expect("Could not send event");
+                service.request_key().await.expect("Could not request a key");
             },
+            #[cfg(feature = "security")]
             "reset" => {
-                let next_msg_id = service.next_msg_id.load(std::sync::atomic::Ordering::Relaxed).to_string();
-                service.send_event(&next_msg_id, "_reset", BTreeMap::default()).await.expect("Could not send event");
+                service.reset_key().await.expect("Could not reset the key");
             },
             "announce" => {
                 service.announce().await.expect("Could not announce to server");
             },
             "announcehttp" => {
-                service.announce_http(&ANNOUNCE_ENDPOINT).await.expect("Could not announce to server");
+                service.announce_http().await.expect("Could not announce to server");
             },
             "help" => {
                 println!("Commands:");
                 println!("  announce - send a new announce to the server");
                 println!("  announcehttp - send a new announce to the server over HTTP");
+                #[cfg(feature = "security")]
                 println!("  getkey - request a key from the server");
+                #[cfg(feature = "security")]
                 println!("  reset - reset the encryption settings on the server");
                 println!("  quit - exit the program");
             },
             "quit" => {
+                if let Err(e) = service.shutdown(Duration::from_secs(1), true).await {
+                    println!("Could not shut down cleanly: {}", e);
+                }
                 break;
             },
             _ => {
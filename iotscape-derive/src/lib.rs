@@ -0,0 +1,131 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, LitStr};
+
+/// Derive `iotscape::IntoNetsBlox`
+///
+/// Structs with named fields become tables of `[name, value]` rows, tuple structs become
+/// lists of their fields, and fieldless enum variants become their name as text.
+/// Fields accept `#[netsblox(rename = "...")]` and `#[netsblox(skip)]`, enum variants accept
+/// `#[netsblox(rename = "...")]`.
+#[proc_macro_derive(IntoNetsBlox, attributes(netsblox))]
+pub fn derive_into_netsblox(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::iotscape::IntoNetsBlox));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => expand_fields(&data.fields, quote!(#name))?,
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    if !matches!(variant.fields, Fields::Unit) {
+                        return Err(Error::new_spanned(variant, "IntoNetsBlox can only be derived for enums with unit variants"));
+                    }
+
+                    let ident = &variant.ident;
+                    let attrs = parse_attrs(&variant.attrs)?;
+                    if attrs.skip {
+                        return Err(Error::new_spanned(variant, "enum variants cannot be skipped"));
+                    }
+                    let label = attrs.rename.unwrap_or_else(|| ident.to_string());
+                    Ok(quote!(#name::#ident => ::iotscape::IntoNetsBlox::into_netsblox(#label)))
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+
+            quote! {
+                match self {
+                    #(#arms,)*
+                }
+            }
+        }
+        Data::Union(_) => return Err(Error::new_spanned(&input, "IntoNetsBlox cannot be derived for unions")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::iotscape::IntoNetsBlox for #name #ty_generics #where_clause {
+            fn into_netsblox(self) -> ::iotscape::Value {
+                #body
+            }
+        }
+    })
+}
+
+fn expand_fields(fields: &Fields, path: TokenStream2) -> syn::Result<TokenStream2> {
+    let mut bindings = Vec::new();
+    let mut values = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let attrs = parse_attrs(&field.attrs)?;
+        let binding = format_ident!("field_{}", i);
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        };
+
+        if attrs.skip {
+            bindings.push(quote!(#member: _));
+            continue;
+        }
+
+        bindings.push(quote!(#member: #binding));
+        values.push(match &field.ident {
+            Some(ident) => {
+                let label = attrs.rename.unwrap_or_else(|| ident.to_string());
+                quote!(::iotscape::IntoNetsBlox::into_netsblox((#label, #binding)))
+            }
+            None => quote!(::iotscape::IntoNetsBlox::into_netsblox(#binding)),
+        });
+    }
+
+    if values.is_empty() {
+        return Ok(quote! {
+            let #path { #(#bindings,)* } = self;
+            ::iotscape::IntoNetsBlox::into_netsblox(())
+        });
+    }
+
+    Ok(quote! {
+        let #path { #(#bindings,)* } = self;
+        ::iotscape::IntoNetsBlox::into_netsblox([#(#values),*])
+    })
+}
+
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    skip: bool,
+}
+
+fn parse_attrs(attrs: &[syn::Attribute]) -> syn::Result<Attrs> {
+    let mut parsed = Attrs::default();
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("netsblox")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                parsed.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else if meta.path.is_ident("skip") {
+                parsed.skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported netsblox attribute"))
+            }
+        })?;
+    }
+
+    Ok(parsed)
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use iotscape::IntoNetsBlox;
+use serde_json::json;
+
+#[derive(IntoNetsBlox)]
+struct Reading {
+    sensor: String,
+    #[netsblox(rename = "value")]
+    reading: f64,
+    #[netsblox(skip)]
+    #[allow(dead_code)]
+    raw: u16,
+    tags: Vec<&'static str>,
+}
+
+#[derive(IntoNetsBlox)]
+struct Point<T>(T, T);
+
+#[derive(IntoNetsBlox)]
+struct Empty;
+
+#[derive(IntoNetsBlox)]
+enum State {
+    Idle,
+    #[netsblox(rename = "busy")]
+    Working,
+}
+
+#[test]
+fn named_struct_as_table() {
+    let reading = Reading {
+        sensor: "temp".to_owned(),
+        reading: 21.5,
+        raw: 3,
+        tags: vec!["a", "b"],
+    };
+    assert_eq!(
+        reading.into_netsblox(),
+        json!([["sensor", "temp"], ["value", 21.5], ["tags", ["a", "b"]]])
+    );
+}
+
+#[test]
+fn tuple_and_unit_structs() {
+    assert_eq!(Point(1, 2).into_netsblox(), json!([1, 2]));
+    assert_eq!(Empty.into_netsblox(), json!([]));
+}
+
+#[test]
+fn unit_enum_as_text() {
+    assert_eq!(State::Idle.into_netsblox(), json!("Idle"));
+    assert_eq!(State::Working.into_netsblox(), json!("busy"));
+}
+
+#[test]
+fn nested_data() {
+    let data = (
+        "test",
+        vec![1, 2, 3],
+        BTreeMap::from([("x", Some(1)), ("y", None)]),
+    );
+    assert_eq!(
+        data.into_response(),
+        vec![json!(["test", [1, 2, 3], [["x", 1], ["y", ""]]])]
+    );
+    assert!(().into_response().is_empty());
+}
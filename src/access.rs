@@ -0,0 +1,92 @@
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+};
+use core::fmt;
+
+use crate::Request;
+
+/// Function deciding whether a request may be handled
+pub type AccessHook = Box<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// Which clients may call a method
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessRule {
+    /// Only these clients may call the method, any client not denied may if not set
+    pub allow: Option<BTreeSet<String>>,
+    pub deny: BTreeSet<String>,
+}
+
+impl AccessRule {
+    /// Rule allowing only the given clients
+    pub fn allow(clients: &[&str]) -> Self {
+        Self {
+            allow: Some(clients.iter().map(|c| (*c).to_owned()).collect()),
+            deny: BTreeSet::new(),
+        }
+    }
+
+    /// Rule allowing every client except the given ones
+    pub fn deny(clients: &[&str]) -> Self {
+        Self {
+            allow: None,
+            deny: clients.iter().map(|c| (*c).to_owned()).collect(),
+        }
+    }
+
+    /// Check a client against the rule, requests without a client id only pass rules with no allow list
+    pub fn permits(&self, client_id: Option<&str>) -> bool {
+        match client_id {
+            Some(id) => !self.deny.contains(id) && self.allow.as_ref().is_none_or(|allow| allow.contains(id)),
+            None => self.allow.is_none(),
+        }
+    }
+}
+
+/// Authorization of requests before they reach handlers
+///
+/// Methods without a rule may be called by anyone, unless the hook rejects the request.
+#[derive(Default)]
+pub struct AccessControl {
+    pub rules: BTreeMap<String, AccessRule>,
+    hook: Option<AccessHook>,
+}
+
+impl fmt::Debug for AccessControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessControl")
+            .field("rules", &self.rules)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+impl AccessControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the rule for a method
+    pub fn with_rule(mut self, method: &str, rule: AccessRule) -> Self {
+        self.rules.insert(method.to_owned(), rule);
+        self
+    }
+
+    /// Set a function to check requests that pass the rules, returning false to reject them
+    pub fn with_hook(mut self, hook: impl Fn(&Request) -> bool + Send + Sync + 'static) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Check whether a request may be handled
+    pub fn authorize(&self, request: &Request) -> bool {
+        let permitted = self
+            .rules
+            .get(&request.function)
+            .is_none_or(|rule| rule.permits(request.client_id.as_deref()));
+
+        permitted && self.hook.as_ref().is_none_or(|hook| hook(request))
+    }
+}
@@ -0,0 +1,35 @@
+use alloc::vec::Vec;
+use std::sync::Mutex;
+
+/// Buffers kept beyond this count are dropped instead of pooled
+const MAX_POOLED: usize = 16;
+
+/// Buffers reused between sends and receives, so each one does not allocate
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Take an empty buffer
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Take a buffer of the given length, zeroed
+    #[cfg(feature = "async")]
+    pub(crate) fn take_sized(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.take();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Return a buffer for reuse
+    pub(crate) fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Where a service finds the NetsBlox server, from code, environment variables or a config file
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::String,
+    vec::Vec,
+};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(feature = "http_announce", feature = "http_response"))]
+use crate::http::HttpConfig;
+use crate::resolve::{resolve, ServerResolver};
+
+/// Environment variable naming a config file to load
+pub const CONFIG_ENV: &str = "IOTSCAPE_CONFIG";
+/// Environment variable choosing a preset, `netsblox` or `local`
+pub const PRESET_ENV: &str = "IOTSCAPE_PRESET";
+/// Environment variable overriding the UDP server
+pub const SERVER_ENV: &str = "IOTSCAPE_SERVER";
+/// Environment variable setting the fallback UDP servers, separated by commas
+pub const FALLBACK_SERVERS_ENV: &str = "IOTSCAPE_FALLBACK_SERVERS";
+/// Environment variable overriding the HTTP announce URL
+pub const ANNOUNCE_ENDPOINT_ENV: &str = "IOTSCAPE_ANNOUNCE_ENDPOINT";
+/// Environment variable overriding the HTTP response URL
+pub const RESPONSE_ENDPOINT_ENV: &str = "IOTSCAPE_RESPONSE_ENDPOINT";
+/// Environment variable setting the WebSocket URL
+pub const WEBSOCKET_URL_ENV: &str = "IOTSCAPE_WEBSOCKET_URL";
+
+/// Addresses of the NetsBlox server a service talks to
+///
+/// Start from a preset, [`IoTScapeConfig::netsblox`] or [`IoTScapeConfig::local`], then apply a
+/// config file or the environment on top. [`IoTScapeConfig::load`] does this for the public server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IoTScapeConfig {
+    /// UDP server as `host:port`, where the host may be a DNS name
+    pub server: String,
+    /// UDP servers to move to, in order, if `server` stops answering
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_servers: Vec<String>,
+    /// URL services are announced to over HTTP
+    pub announce_url: String,
+    /// URL responses are sent to over HTTP
+    pub response_url: String,
+    /// URL of a relay passing WebSocket messages on to the UDP server, not set in the presets as
+    /// the NetsBlox server has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_url: Option<String>,
+}
+
+impl IoTScapeConfig {
+    /// The public NetsBlox server
+    pub fn netsblox() -> Self {
+        Self {
+            server: "services.netsblox.org:1978".to_owned(),
+            fallback_servers: Vec::new(),
+            announce_url: "https://services.netsblox.org/routes/iotscape/announce".to_owned(),
+            response_url: "https://services.netsblox.org/routes/iotscape/response".to_owned(),
+            websocket_url: None,
+        }
+    }
+
+    /// A NetsBlox server running on this machine with its default ports
+    pub fn local() -> Self {
+        Self {
+            server: "127.0.0.1:1978".to_owned(),
+            fallback_servers: Vec::new(),
+            announce_url: "http://localhost:8080/routes/iotscape/announce".to_owned(),
+            response_url: "http://localhost:8080/routes/iotscape/response".to_owned(),
+            websocket_url: None,
+        }
+    }
+
+    /// A preset by name, `netsblox` or `local`
+    pub fn preset(name: &str) -> Result<Self, String> {
+        match name {
+            "netsblox" => Ok(Self::netsblox()),
+            "local" => Ok(Self::local()),
+            _ => Err(format!("Unknown preset {}", name)),
+        }
+    }
+
+    /// Set the UDP server, as `host:port`
+    pub fn with_server(mut self, server: &str) -> Self {
+        self.server = server.to_owned();
+        self
+    }
+
+    /// Add a UDP server to move to if the ones before it stop answering, as `host:port`
+    pub fn with_fallback(mut self, server: &str) -> Self {
+        self.fallback_servers.push(server.to_owned());
+        self
+    }
+
+    /// Set the URLs for announces and responses over HTTP
+    pub fn with_urls(mut self, announce_url: &str, response_url: &str) -> Self {
+        self.announce_url = announce_url.to_owned();
+        self.response_url = response_url.to_owned();
+        self
+    }
+
+    /// Set the URL of a relay to talk to the server through over a WebSocket
+    pub fn with_websocket_url(mut self, url: &str) -> Self {
+        self.websocket_url = Some(url.to_owned());
+        self
+    }
+
+    /// Apply a JSON config file on top of this config
+    ///
+    /// The file may name a `preset` to start from and set any of `server`, `fallbackServers`,
+    /// `announceUrl`, `responseUrl` and `websocketUrl`.
+    pub fn with_file(self, path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let overrides: Overrides =
+            serde_json::from_slice(&content).map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        self.apply(overrides)
+    }
+
+    /// Apply the `IOTSCAPE_*` environment variables that are set on top of this config
+    ///
+    /// `IOTSCAPE_PRESET` replaces the config first, then the file named by `IOTSCAPE_CONFIG` is
+    /// applied, then `IOTSCAPE_SERVER`, `IOTSCAPE_FALLBACK_SERVERS`, `IOTSCAPE_ANNOUNCE_ENDPOINT`,
+    /// `IOTSCAPE_RESPONSE_ENDPOINT` and `IOTSCAPE_WEBSOCKET_URL`.
+    pub fn with_env(self) -> Result<Self, String> {
+        let var = |name| std::env::var(name).ok();
+        let mut config = match var(PRESET_ENV) {
+            Some(preset) => Self::preset(&preset)?,
+            None => self,
+        };
+        if let Some(path) = var(CONFIG_ENV) {
+            config = config.with_file(path)?;
+        }
+        config.apply(Overrides {
+            preset: None,
+            server: var(SERVER_ENV),
+            fallback_servers: var(FALLBACK_SERVERS_ENV)
+                .map(|servers| servers.split(',').map(|s| s.trim().to_owned()).filter(|s| !s.is_empty()).collect()),
+            announce_url: var(ANNOUNCE_ENDPOINT_ENV),
+            response_url: var(RESPONSE_ENDPOINT_ENV),
+            websocket_url: var(WEBSOCKET_URL_ENV),
+        })
+    }
+
+    /// The public NetsBlox server, changed by the `IOTSCAPE_*` environment variables that are set
+    pub fn load() -> Result<Self, String> {
+        Self::default().with_env()
+    }
+
+    fn apply(self, overrides: Overrides) -> Result<Self, String> {
+        let mut config = match overrides.preset {
+            Some(preset) => Self::preset(&preset)?,
+            None => self,
+        };
+        if let Some(server) = overrides.server {
+            config.server = server;
+        }
+        if let Some(servers) = overrides.fallback_servers {
+            config.fallback_servers = servers;
+        }
+        if let Some(url) = overrides.announce_url {
+            config.announce_url = url;
+        }
+        if let Some(url) = overrides.response_url {
+            config.response_url = url;
+        }
+        if let Some(url) = overrides.websocket_url {
+            config.websocket_url = Some(url);
+        }
+        Ok(config)
+    }
+
+    /// Look up the UDP server's address, preferring IPv4 as services bind an IPv4 socket
+    pub fn server_addr(&self) -> Result<SocketAddr, String> {
+        resolve(&self.server)
+    }
+
+    /// A resolver for the UDP server and its fallbacks, to keep a service pointed at whichever
+    /// is up
+    pub fn resolver(&self) -> ServerResolver {
+        self.fallback_servers
+            .iter()
+            .fold(ServerResolver::new(&self.server), |resolver, server| resolver.with_fallback(server))
+    }
+
+    /// HTTP settings for the announce and response URLs
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    pub fn http_config(&self) -> HttpConfig {
+        HttpConfig::with_urls(&self.announce_url, &self.response_url)
+    }
+}
+
+impl Default for IoTScapeConfig {
+    fn default() -> Self {
+        Self::netsblox()
+    }
+}
+
+/// Settings from a config file or the environment, each replacing the current one if set
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Overrides {
+    preset: Option<String>,
+    server: Option<String>,
+    fallback_servers: Option<Vec<String>>,
+    announce_url: Option<String>,
+    response_url: Option<String>,
+    websocket_url: Option<String>,
+}
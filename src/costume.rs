@@ -0,0 +1,120 @@
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::String,
+    vec::Vec,
+};
+
+use base64::Engine;
+use serde_json::Value;
+
+/// A NetsBlox costume, built from encoded image data, to be sent as a response value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Costume {
+    pub name: String,
+    /// Rotation center in pixels, NetsBlox uses the middle of the image if not set
+    pub center: Option<(f64, f64)>,
+    mime_type: &'static str,
+    data: Vec<u8>,
+}
+
+impl Costume {
+    /// Create a costume from PNG encoded data
+    pub fn from_png(data: Vec<u8>) -> Self {
+        Self::with_mime_type("image/png", data)
+    }
+
+    /// Create a costume from JPEG encoded data
+    pub fn from_jpeg(data: Vec<u8>) -> Self {
+        Self::with_mime_type("image/jpeg", data)
+    }
+
+    /// Create a costume from PNG or JPEG data, detecting the format from its header
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Ok(Self::from_png(data))
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Ok(Self::from_jpeg(data))
+        } else {
+            Err("Unsupported image format, expected PNG or JPEG".to_owned())
+        }
+    }
+
+    /// Create a costume from an `image` crate buffer, encoded as PNG
+    #[cfg(feature = "costume_image")]
+    pub fn from_image(image: &image::DynamicImage) -> Result<Self, String> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut data, image::ImageFormat::Png)
+            .map_err(|e| format!("Could not encode image: {}", e))?;
+        Ok(Self::from_png(data.into_inner()))
+    }
+
+    fn with_mime_type(mime_type: &'static str, data: Vec<u8>) -> Self {
+        Self {
+            name: "costume".to_owned(),
+            center: None,
+            mime_type,
+            data,
+        }
+    }
+
+    /// Set the costume name shown in NetsBlox
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    /// Set the rotation center, in pixels from the top left corner
+    pub fn with_center(mut self, x: f64, y: f64) -> Self {
+        self.center = Some((x, y));
+        self
+    }
+
+    /// Encoded image data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// MIME type of the encoded image data
+    pub fn mime_type(&self) -> &'static str {
+        self.mime_type
+    }
+
+    /// Costume XML in the format NetsBlox expects
+    pub fn to_xml(&self) -> String {
+        let center = match self.center {
+            Some((x, y)) => format!(" center-x=\"{}\" center-y=\"{}\"", x, y),
+            None => String::new(),
+        };
+
+        format!(
+            "<costume name=\"{}\" collabId=\"\"{} image=\"data:{};base64,{}\"/>",
+            escape_attribute(&self.name),
+            center,
+            self.mime_type,
+            base64::engine::general_purpose::STANDARD.encode(&self.data)
+        )
+    }
+}
+
+impl From<Costume> for Value {
+    fn from(costume: Costume) -> Self {
+        Value::String(costume.to_xml())
+    }
+}
+
+/// Escape text for use inside a double-quoted XML attribute
+fn escape_attribute(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
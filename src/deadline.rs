@@ -0,0 +1,68 @@
+use alloc::{string::String, vec::Vec};
+use core::time::Duration;
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use crate::Request;
+
+/// How long the id of a timed out request is kept to drop a late response
+const EXPIRED_RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Deadline {
+    at: Instant,
+    service: String,
+    expired: bool,
+}
+
+/// Deadlines of requests handed to handlers, by request id
+#[derive(Debug, Default)]
+pub(crate) struct Deadlines {
+    entries: Mutex<HashMap<String, Deadline>>,
+}
+
+impl Deadlines {
+    /// Start the clock on a request
+    pub(crate) fn start(&self, request: &Request, timeout: Duration) {
+        self.entries.lock().unwrap().insert(request.id.clone(), Deadline {
+            at: Instant::now() + timeout,
+            service: request.service.clone(),
+            expired: false,
+        });
+    }
+
+    /// Mark requests past their deadline as expired, returning their ids and services
+    pub(crate) fn expire(&self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, d| !d.expired || now.saturating_duration_since(d.at) < EXPIRED_RETENTION);
+
+        entries
+            .iter_mut()
+            .filter(|(_, d)| !d.expired && d.at <= now)
+            .map(|(id, d)| {
+                d.expired = true;
+                (id.clone(), d.service.clone())
+            })
+            .collect()
+    }
+
+    /// Earliest deadline of the requests still being handled
+    #[cfg(feature = "async")]
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|d| !d.expired)
+            .map(|d| d.at)
+            .min()
+    }
+
+    /// Stop the clock on a request, returning false if it already timed out
+    pub(crate) fn finish(&self, request_id: &str) -> bool {
+        match self.entries.lock().unwrap().remove(request_id) {
+            Some(d) => !d.expired,
+            None => true,
+        }
+    }
+}
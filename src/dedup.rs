@@ -0,0 +1,87 @@
+use alloc::{borrow::ToOwned, string::String};
+use core::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::Response;
+
+/// What to do with a request, based on whether its id was seen before
+#[derive(Debug, Clone)]
+pub enum Seen {
+    /// First time the request was seen, it should be handled
+    New,
+    /// The request is still being handled, the retransmission should be ignored
+    Pending,
+    /// The request was already answered, the response should be sent again
+    Answered(Response),
+}
+
+#[derive(Debug)]
+struct Entry {
+    seen: Instant,
+    response: Option<Response>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    by_request: HashMap<String, Entry>,
+    last_pruned: Instant,
+}
+
+/// Cache of responses by request id, so retransmitted requests are not handled twice
+#[derive(Debug)]
+pub struct ResponseCache {
+    /// How long a request id is remembered after it was first seen
+    pub ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(Entries {
+                by_request: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Record a request id, returning whether it was seen before
+    pub fn check(&self, request_id: &str) -> Seen {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        // Forget expired requests now and then rather than on every call
+        if now.saturating_duration_since(entries.last_pruned) > self.ttl / 2 {
+            entries.by_request.retain(|_, entry| now.saturating_duration_since(entry.seen) <= self.ttl);
+            entries.last_pruned = now;
+        }
+
+        match entries.by_request.get(request_id) {
+            Some(entry) if now.saturating_duration_since(entry.seen) <= self.ttl => match &entry.response {
+                Some(response) => Seen::Answered(response.clone()),
+                None => Seen::Pending,
+            },
+            _ => {
+                entries.by_request.insert(request_id.to_owned(), Entry { seen: now, response: None });
+                Seen::New
+            }
+        }
+    }
+
+    /// Forget a request that will not be answered, so a retransmission is handled as new
+    pub fn forget(&self, request_id: &str) {
+        self.entries.lock().unwrap().by_request.remove(request_id);
+    }
+
+    /// Remember the response to a request, if the request is known
+    pub fn store(&self, response: &Response) {
+        if let Some(entry) = self.entries.lock().unwrap().by_request.get_mut(&response.request) {
+            entry.response = Some(response.clone());
+        }
+    }
+}
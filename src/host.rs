@@ -0,0 +1,192 @@
+use alloc::{
+    borrow::ToOwned,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::time::Duration;
+use std::{net::SocketAddr, sync::Mutex};
+
+use log::{error, trace};
+use serde::Deserialize;
+
+use crate::{buffer::BufferPool, socket::SocketTrait, IoTScapeService, ServiceDefinition, StdUdpSocket, MAX_DATAGRAM_SIZE};
+
+/// Datagrams waiting to be read by one hosted service
+type Inbox = Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>;
+
+/// Fields of a request used to route it to a hosted service
+#[derive(Deserialize)]
+struct Route {
+    service: String,
+    device: String,
+}
+
+/// Socket of a service on an [`IoTScapeHost`], sending through the host's socket and receiving
+/// only the datagrams routed to the service
+pub struct HostedSocket<SocketType: SocketTrait> {
+    socket: Arc<SocketType>,
+    inbox: Inbox,
+    buffers: Arc<BufferPool>,
+}
+
+impl<SocketType: SocketTrait> SocketTrait for HostedSocket<SocketType> {
+    fn bind(_addrs: &[SocketAddr]) -> Result<Self, String> {
+        Err("Hosted sockets are created by IoTScapeHost".to_owned())
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, String> {
+        self.socket.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), String> {
+        match self.inbox.lock().unwrap().pop_front() {
+            Some((from, packet)) => {
+                let size = packet.len();
+                if size > buf.len() {
+                    return Err(format!("Datagram of {} bytes is too large", size));
+                }
+                buf[..size].copy_from_slice(&packet);
+                self.buffers.put(packet);
+                Ok((size, from))
+            }
+            None => Err("No packets".to_owned()),
+        }
+    }
+
+    // Timeouts are set on the host's socket
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Service on an [`IoTScapeHost`]
+pub type HostedService<SocketType> = IoTScapeService<HostedSocket<SocketType>>;
+
+/// Several services or devices sharing one socket
+///
+/// Requests are routed by service name and device id to the matching service, which handles them
+/// as if it had its own socket. Handlers take requests from each service's `rx_queue` as usual.
+pub struct IoTScapeHost<SocketType: SocketTrait = StdUdpSocket> {
+    server: SocketAddr,
+    socket: Arc<SocketType>,
+    services: BTreeMap<(String, String), (HostedService<SocketType>, Inbox)>,
+    recv_buf: Vec<u8>,
+    /// Buffers for datagrams waiting in inboxes, returned once a service reads them
+    buffers: Arc<BufferPool>,
+}
+
+impl<SocketType: SocketTrait> IoTScapeHost<SocketType> {
+    pub fn new(server: SocketAddr) -> Self {
+        let addrs = [
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
+        ];
+
+        Self {
+            server,
+            socket: Arc::new(SocketType::bind(&addrs[..]).unwrap()),
+            services: BTreeMap::new(),
+            recv_buf: alloc::vec![0u8; MAX_DATAGRAM_SIZE],
+            buffers: Arc::default(),
+        }
+    }
+
+    /// The socket shared by the hosted services
+    pub fn socket(&self) -> &SocketType {
+        &self.socket
+    }
+
+    /// Host a service as the device with the definition's id, replacing any with the same name and id
+    pub fn add_service(&mut self, name: &str, definition: ServiceDefinition) -> &mut HostedService<SocketType> {
+        let key = (name.to_owned(), definition.id.clone());
+        let inbox = Inbox::default();
+        let socket = HostedSocket {
+            socket: Arc::clone(&self.socket),
+            inbox: Arc::clone(&inbox),
+            buffers: Arc::clone(&self.buffers),
+        };
+        let service = HostedService::with_socket(name, definition, self.server, socket);
+
+        self.services.insert(key.clone(), (service, inbox));
+        &mut self.services.get_mut(&key).unwrap().0
+    }
+
+    /// Stop hosting a device, returning its service
+    pub fn remove_service(&mut self, name: &str, device: &str) -> Option<HostedService<SocketType>> {
+        self.services
+            .remove(&(name.to_owned(), device.to_owned()))
+            .map(|(service, _)| service)
+    }
+
+    pub fn service(&self, name: &str, device: &str) -> Option<&HostedService<SocketType>> {
+        self.services
+            .get(&(name.to_owned(), device.to_owned()))
+            .map(|(service, _)| service)
+    }
+
+    pub fn service_mut(&mut self, name: &str, device: &str) -> Option<&mut HostedService<SocketType>> {
+        self.services
+            .get_mut(&(name.to_owned(), device.to_owned()))
+            .map(|(service, _)| service)
+    }
+
+    /// All hosted services
+    pub fn services_mut(&mut self) -> impl Iterator<Item = &mut HostedService<SocketType>> {
+        self.services.values_mut().map(|(service, _)| service)
+    }
+
+    /// Announce every hosted device to the server, stopping at the first failure
+    pub fn announce_all(&mut self) -> Result<(), String> {
+        for service in self.services_mut() {
+            service.announce()?;
+        }
+        Ok(())
+    }
+
+    /// Receive requests for all hosted services, then let each handle its own and send its responses
+    pub fn poll(&mut self, timeout: Option<Duration>) {
+        self.socket
+            .set_read_timeout(timeout.or(Some(Duration::from_millis(15))))
+            .unwrap();
+        self.socket
+            .set_write_timeout(timeout.or(Some(Duration::from_millis(15))))
+            .unwrap();
+
+        let mut buf = core::mem::take(&mut self.recv_buf);
+        while let Ok((size, from)) = self.socket.recv_from(&mut buf) {
+            self.route(from, &buf[..size]);
+        }
+        self.recv_buf = buf;
+
+        for (service, _) in self.services.values_mut() {
+            service.poll(timeout);
+        }
+    }
+
+    /// Put a datagram in the inbox of the service it is addressed to
+    fn route(&self, from: SocketAddr, content: &[u8]) {
+        let route = match serde_json::from_slice::<Route>(content) {
+            Ok(route) => route,
+            Err(e) => {
+                error!("Error parsing request: {}", e);
+                return;
+            }
+        };
+
+        match self.services.get(&(route.service, route.device)) {
+            Some((_, inbox)) => {
+                let mut packet = self.buffers.take();
+                packet.extend_from_slice(content);
+                inbox.lock().unwrap().push_back((from, packet));
+            }
+            None => trace!("Dropping request for a device not hosted here"),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! HTTP clients used to announce and send responses over HTTP
+//!
+//! Where requests go and how long they may take is set once in an [`HttpConfig`]. The requests
+//! are sent by an [`HttpTransport`], implemented for `reqwest` and `ureq` clients behind the
+//! features of the same name, and by [`RawHttp`] for network stacks without an HTTP client.
+
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::String,
+    vec::Vec,
+};
+use core::time::Duration;
+
+#[cfg(any(feature = "reqwest", feature = "ureq"))]
+use alloc::string::ToString;
+
+#[cfg(feature = "async")]
+use futures::future::BoxFuture;
+
+/// Time allowed for a request, and for connecting, unless configured otherwise
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Error for HTTP calls on a service without an [`HttpClient`]
+pub const NO_HTTP_ERROR: &str = "No HTTP client set";
+
+/// Where a service sends announces and responses over HTTP, and how long it waits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// URL the paths below are relative to, e.g. `https://services.netsblox.org/routes/iotscape`
+    pub base_url: String,
+    /// Path of the announce route, or a full URL used as is
+    pub announce_path: String,
+    /// Path of the response route, or a full URL used as is
+    pub response_path: String,
+    /// Time allowed for a whole request
+    pub timeout: Duration,
+    /// Time allowed for connecting to the server
+    pub connect_timeout: Duration,
+    /// Skip checking the server's certificate, for local servers with self-signed ones
+    ///
+    /// Only used with the `tls` feature.
+    pub accept_invalid_certs: bool,
+}
+
+impl HttpConfig {
+    /// Settings for the NetsBlox IoTScape routes under a base URL
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_owned(),
+            announce_path: "announce".to_owned(),
+            response_path: "response".to_owned(),
+            timeout: DEFAULT_HTTP_TIMEOUT,
+            connect_timeout: DEFAULT_HTTP_TIMEOUT,
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// Settings for separately given announce and response URLs
+    pub fn with_urls(announce_url: &str, response_url: &str) -> Self {
+        Self {
+            base_url: String::new(),
+            announce_path: announce_url.to_owned(),
+            response_path: response_url.to_owned(),
+            ..Self::new("")
+        }
+    }
+
+    /// Set the time allowed for a whole request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the time allowed for connecting to the server
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set whether the server's certificate is checked
+    pub fn with_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn announce_url(&self) -> String {
+        self.url(&self.announce_path)
+    }
+
+    pub fn response_url(&self) -> String {
+        self.url(&self.response_path)
+    }
+
+    fn url(&self, path: &str) -> String {
+        if path.contains("://") {
+            return path.to_owned();
+        }
+
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path.trim_start_matches('/'))
+    }
+}
+
+/// Status and body of the server's answer to an HTTP request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Whether the status is in the 2xx range
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends HTTP requests for [`IoTScapeService`](crate::IoTScapeService)
+pub trait HttpTransport {
+    /// Send a JSON body to a URL with a POST request
+    fn post_json(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse, String>;
+}
+
+/// HTTP settings and the transport using them, for a service's announces and responses over HTTP
+pub struct HttpClient {
+    config: HttpConfig,
+    transport: Box<dyn HttpTransport + Send>,
+}
+
+impl HttpClient {
+    /// Client using `reqwest` if enabled, `ureq` otherwise, set up from the config
+    #[cfg(any(feature = "reqwest", feature = "ureq"))]
+    pub fn new(config: HttpConfig) -> Self {
+        #[cfg(feature = "reqwest")]
+        let transport = reqwest_client(&config);
+        #[cfg(not(feature = "reqwest"))]
+        let transport = ureq_agent(&config);
+
+        Self::with_transport(config, transport)
+    }
+
+    /// Client sending requests through the given transport, which should apply the config's
+    /// timeouts itself
+    pub fn with_transport(config: HttpConfig, transport: impl HttpTransport + Send + 'static) -> Self {
+        Self {
+            config,
+            transport: Box::new(transport),
+        }
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// Send a service definition to the announce URL
+    pub fn announce(&self, body: Vec<u8>) -> Result<HttpResponse, String> {
+        self.transport.post_json(&self.config.announce_url(), body)
+    }
+
+    /// Send a response to the response URL
+    pub fn respond(&self, body: Vec<u8>) -> Result<HttpResponse, String> {
+        self.transport.post_json(&self.config.response_url(), body)
+    }
+}
+
+impl core::fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HttpClient").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+/// HTTP client for [`IoTScapeServiceAsync`](crate::IoTScapeServiceAsync), independent of the async runtime
+///
+/// With the `tokio` feature this is implemented for `reqwest::Client`. Blocking clients,
+/// `reqwest::blocking::Client` with the `smol` feature and `ureq::Agent`, run requests on a
+/// thread pool and so work with any runtime, though the blocking `reqwest` client still runs
+/// tokio internally.
+#[cfg(feature = "async")]
+pub trait HttpTransportAsync: Send + Sync {
+    /// Send a JSON body to a URL with a POST request
+    fn post_json<'a>(&'a self, url: &'a str, body: Vec<u8>) -> BoxFuture<'a, Result<HttpResponse, std::io::Error>>;
+}
+
+/// HTTP settings and the transport using them, for an async service's announces and responses over HTTP
+#[cfg(feature = "async")]
+pub struct HttpClientAsync {
+    config: HttpConfig,
+    transport: Box<dyn HttpTransportAsync>,
+}
+
+#[cfg(feature = "async")]
+impl HttpClientAsync {
+    /// Client set up from the config, using the async `reqwest` client with the `tokio` feature
+    /// and `ureq` otherwise
+    ///
+    /// Without `tokio`, the blocking `reqwest` client is only used if `ureq` is not enabled. It
+    /// starts a tokio runtime of its own on a background thread, so enable `ureq` to keep tokio
+    /// out of `smol` builds.
+    #[cfg(any(feature = "reqwest", feature = "ureq"))]
+    pub fn new(config: HttpConfig) -> Self {
+        #[cfg(all(feature = "reqwest", feature = "tokio"))]
+        let transport = reqwest_client_async(&config);
+        #[cfg(all(feature = "ureq", not(all(feature = "reqwest", feature = "tokio"))))]
+        let transport = ureq_agent(&config);
+        #[cfg(all(feature = "reqwest", not(feature = "tokio"), not(feature = "ureq")))]
+        let transport = reqwest_client(&config);
+
+        Self::with_transport(config, transport)
+    }
+
+    /// Client sending requests through the given transport, which should apply the config's
+    /// timeouts itself
+    pub fn with_transport(config: HttpConfig, transport: impl HttpTransportAsync + 'static) -> Self {
+        Self {
+            config,
+            transport: Box::new(transport),
+        }
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// Send a service definition to the announce URL
+    pub async fn announce(&self, body: Vec<u8>) -> Result<HttpResponse, std::io::Error> {
+        self.transport.post_json(&self.config.announce_url(), body).await
+    }
+
+    /// Send a response to the response URL
+    pub async fn respond(&self, body: Vec<u8>) -> Result<HttpResponse, std::io::Error> {
+        self.transport.post_json(&self.config.response_url(), body).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl core::fmt::Debug for HttpClientAsync {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HttpClientAsync").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+/// Blocking `reqwest` client with the config's timeouts, kept for all requests of a service
+#[cfg(feature = "reqwest")]
+pub fn reqwest_client(config: &HttpConfig) -> reqwest::blocking::Client {
+    let builder = reqwest::blocking::Client::builder()
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout);
+    #[cfg(feature = "tls")]
+    let builder = builder.danger_accept_invalid_certs(config.accept_invalid_certs);
+    builder.build().expect("Could not create HTTP client")
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpTransport for reqwest::blocking::Client {
+    fn post_json(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse, String> {
+        let response = self.post(url)
+            .body(body)
+            .header("Content-Type", "application/json")
+            .send()
+            .map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let body = response.bytes().map_err(|e| e.to_string())?;
+        Ok(HttpResponse { status, body: body.to_vec() })
+    }
+}
+
+/// Async `reqwest` client with the config's timeouts, kept for all requests of a service
+#[cfg(all(feature = "reqwest", feature = "tokio"))]
+pub fn reqwest_client_async(config: &HttpConfig) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout);
+    #[cfg(feature = "tls")]
+    let builder = builder.danger_accept_invalid_certs(config.accept_invalid_certs);
+    builder.build().expect("Could not create HTTP client")
+}
+
+#[cfg(all(feature = "reqwest", feature = "tokio"))]
+impl HttpTransportAsync for reqwest::Client {
+    fn post_json<'a>(&'a self, url: &'a str, body: Vec<u8>) -> BoxFuture<'a, Result<HttpResponse, std::io::Error>> {
+        Box::pin(async move {
+            let response = self.post(url)
+                .body(body)
+                .header("Content-Type", "application/json")
+                .send().await
+                .map_err(std::io::Error::other)?;
+            let status = response.status().as_u16();
+            let body = response.bytes().await.map_err(std::io::Error::other)?;
+            Ok(HttpResponse { status, body: body.to_vec() })
+        })
+    }
+}
+
+#[cfg(all(feature = "reqwest", feature = "smol"))]
+impl HttpTransportAsync for reqwest::blocking::Client {
+    fn post_json<'a>(&'a self, url: &'a str, body: Vec<u8>) -> BoxFuture<'a, Result<HttpResponse, std::io::Error>> {
+        let client = self.clone();
+        let url = url.to_owned();
+        Box::pin(blocking::unblock(move || {
+            HttpTransport::post_json(&client, &url, body).map_err(std::io::Error::other)
+        }))
+    }
+}
+
+/// `ureq` agent with the config's timeouts, kept for all requests of a service
+#[cfg(feature = "ureq")]
+pub fn ureq_agent(config: &HttpConfig) -> ureq::Agent {
+    let builder = ureq::Agent::config_builder()
+        .timeout_global(Some(config.timeout))
+        .timeout_connect(Some(config.connect_timeout))
+        .http_status_as_error(false);
+    #[cfg(feature = "tls")]
+    let builder = builder.tls_config(
+        ureq::tls::TlsConfig::builder()
+            .disable_verification(config.accept_invalid_certs)
+            .build(),
+    );
+    builder.build().into()
+}
+
+#[cfg(feature = "ureq")]
+impl HttpTransport for ureq::Agent {
+    fn post_json(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse, String> {
+        let mut response = self.post(url)
+            .header("Content-Type", "application/json")
+            .send(&body[..])
+            .map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let body = response.body_mut().read_to_vec().map_err(|e| e.to_string())?;
+        Ok(HttpResponse { status, body })
+    }
+}
+
+#[cfg(all(feature = "ureq", feature = "async"))]
+impl HttpTransportAsync for ureq::Agent {
+    fn post_json<'a>(&'a self, url: &'a str, body: Vec<u8>) -> BoxFuture<'a, Result<HttpResponse, std::io::Error>> {
+        let agent = self.clone();
+        let url = url.to_owned();
+        Box::pin(blocking::unblock(move || {
+            HttpTransport::post_json(&agent, &url, body).map_err(std::io::Error::other)
+        }))
+    }
+}
+
+/// Server a [`RawHttp`] request goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawTarget<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    /// Whether the URL was `https`, so the connection needs TLS
+    pub tls: bool,
+}
+
+/// HTTP over a connection made by the caller, for network stacks without an HTTP client
+///
+/// The function gets the server to connect to and the bytes of the request. It should send them,
+/// read until the server closes the connection, and return everything it read. Requests are sent
+/// as HTTP/1.0 so servers answer without chunked encoding. Works without `std`.
+pub struct RawHttp<F> {
+    exchange: F,
+}
+
+impl<F> RawHttp<F>
+where
+    F: Fn(RawTarget<'_>, &[u8]) -> Result<Vec<u8>, String>,
+{
+    pub fn new(exchange: F) -> Self {
+        Self { exchange }
+    }
+}
+
+impl<F> HttpTransport for RawHttp<F>
+where
+    F: Fn(RawTarget<'_>, &[u8]) -> Result<Vec<u8>, String>,
+{
+    fn post_json(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(format!("Unsupported URL {}", url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !authority.ends_with(']') => {
+                (host, port.parse().map_err(|_| format!("Invalid port in URL {}", url))?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+
+        let mut request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            path,
+            authority,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+
+        let raw = (self.exchange)(RawTarget { host, port, tls }, &request)?;
+        parse_raw_response(&raw)
+    }
+}
+
+/// Read the status and body of an HTTP/1.x response
+fn parse_raw_response(raw: &[u8]) -> Result<HttpResponse, String> {
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("Incomplete HTTP response")?;
+    let status = core::str::from_utf8(&raw[..header_end])
+        .ok()
+        .and_then(|headers| headers.split("\r\n").next())
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("Malformed HTTP status line")?;
+
+    Ok(HttpResponse {
+        status,
+        body: raw[header_end + 4..].to_vec(),
+    })
+}
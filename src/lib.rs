@@ -1,48 +1,134 @@
 #![no_std]
 #![forbid(unsafe_code)]
 
-mod socket;
+pub mod access;
+#[cfg(feature = "std")]
+mod buffer;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "costume")]
+mod costume;
+#[cfg(feature = "std")]
+mod deadline;
+#[cfg(feature = "std")]
+pub mod dedup;
+#[cfg(feature = "std")]
+pub mod host;
+#[cfg(any(feature = "http_announce", feature = "http_response"))]
+pub mod http;
+mod netsblox;
+#[cfg(feature = "std")]
+pub mod ratelimit;
+#[cfg(feature = "security")]
+pub mod security;
+#[cfg(feature = "std")]
+pub mod resolve;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod socket;
+mod types;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 extern crate alloc;
 
 #[cfg(feature = "std")]
 extern crate std;
 
+#[cfg(all(feature = "async", not(any(feature = "tokio", feature = "smol"))))]
+compile_error!("The `async` feature needs a runtime feature as well, `tokio` or `smol`");
+
 use core::time::Duration;
 
-#[cfg(feature = "tokio")]
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use alloc::{
-    borrow::ToOwned, collections::{BTreeMap, VecDeque}, string::String, vec::Vec
+    borrow::Cow,
+    borrow::ToOwned, collections::{BTreeMap, VecDeque}, format, string::String, vec::Vec
 };
 
-#[cfg(feature = "tokio")]
-use futures::FutureExt;
+#[cfg(feature = "async")]
+use core::sync::atomic::AtomicBool;
+#[cfg(feature = "async")]
+use buffer::BufferPool;
+#[cfg(feature = "async")]
+use crossbeam_queue::SegQueue;
+#[cfg(feature = "async")]
+use futures::{
+    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    future::Either,
+    FutureExt, StreamExt,
+};
 
 use log::{error, trace};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use socket::SocketTrait;
+use serde_json::value::RawValue;
+pub use serde_json::Value;
+pub use socket::SocketTrait;
 
-#[cfg(feature = "tokio")]
-use socket::SocketTraitAsync;
+#[cfg(feature = "async")]
+pub use socket::SocketTraitAsync;
+
+#[cfg(any(feature = "http_announce", feature = "http_response"))]
+use http::{HttpClient, HttpResponse, NO_HTTP_ERROR};
+#[cfg(all(feature = "async", any(feature = "http_announce", feature = "http_response")))]
+use http::HttpClientAsync;
+
+pub use types::IoTScapeType;
+
+#[cfg(feature = "costume")]
+pub use costume::Costume;
+
+pub use netsblox::IntoNetsBlox;
+
+#[cfg(feature = "derive")]
+pub use iotscape_derive::IntoNetsBlox;
+
+use access::AccessControl;
+
+#[cfg(feature = "std")]
+use config::IoTScapeConfig;
+
+#[cfg(feature = "std")]
+use deadline::Deadlines;
+
+#[cfg(feature = "std")]
+use dedup::{ResponseCache, Seen};
+
+#[cfg(feature = "std")]
+use ratelimit::RateLimiter;
+
+#[cfg(feature = "std")]
+use resolve::ServerResolver;
+
+#[cfg(feature = "std")]
+use session::SessionStore;
+
+#[cfg(feature = "security")]
+use security::Security;
+
+#[cfg(feature = "signing")]
+use signing::{SignatureError, Signer};
 
 #[cfg(feature = "std")]
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 #[cfg(not(feature = "std"))]
-use no_std_net::SocketAddr;
+use no_std_net::{IpAddr, SocketAddr};
 
 #[cfg(feature = "std")]
 use std::net::UdpSocket as StdUdpSocket;
 
 #[cfg(feature = "tokio")]
 use tokio::net::UdpSocket as TokioUdpSocket;
-#[cfg(feature = "tokio")]
+#[cfg(feature = "smol")]
+use async_io::Async;
+#[cfg(feature = "async")]
 use alloc::sync::Arc;
 
-#[cfg(all(feature = "tokio", not(feature = "no_deadlocks")))]
+#[cfg(all(feature = "async", not(feature = "no_deadlocks")))]
 use std::sync::Mutex;
 #[cfg(feature = "no_deadlocks")]
 use no_deadlocks::Mutex;
@@ -54,30 +140,74 @@ pub struct Request {
     pub service: String,
     pub device: String,
     pub function: String,
+    #[serde(default)]
     pub params: Vec<serde_json::Value>,
-    #[serde(rename = "clientId")]
+    #[serde(rename = "clientId", default, skip_serializing_if = "Option::is_none")]
     pub client_id: Option<String>,
 }
 
+/// A request borrowing its strings from the datagram it was parsed from where possible
+///
+/// Parameters are kept as unparsed JSON until the request is turned into a [`Request`].
+#[derive(Debug, Deserialize)]
+pub struct RequestRef<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub service: Cow<'a, str>,
+    #[serde(borrow)]
+    pub device: Cow<'a, str>,
+    #[serde(borrow)]
+    pub function: Cow<'a, str>,
+    #[serde(borrow, default)]
+    pub params: Option<&'a RawValue>,
+    #[serde(borrow, rename = "clientId", default)]
+    pub client_id: Option<Cow<'a, str>>,
+}
+
+impl<'a> RequestRef<'a> {
+    pub fn parse(content: &'a [u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(content)
+    }
+
+    /// Copy the strings out of the datagram and parse the parameters
+    pub fn into_owned(self) -> Result<Request, serde_json::Error> {
+        let params = match self.params {
+            Some(params) => serde_json::from_str(params.get())?,
+            None => Vec::new(),
+        };
+
+        Ok(Request {
+            id: self.id.into_owned(),
+            service: self.service.into_owned(),
+            device: self.device.into_owned(),
+            function: self.function.into_owned(),
+            params,
+            client_id: self.client_id.map(Cow::into_owned),
+        })
+    }
+}
+
 /// A response to be sent to the NetsBlox server
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Response {
     pub id: String,
     pub request: String,
     pub service: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub response: Option<Vec<Value>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub event: Option<EventResponse>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
 }
 
 /// Data for an event response to be sent to the server
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EventResponse {
-    pub r#type: Option<String>,
-    pub args: Option<BTreeMap<String, String>>,
+    pub r#type: String,
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
 }
 
 /// Definition of an IoTScape service, to be serialized and set to NetsBlox server
@@ -85,19 +215,85 @@ pub struct EventResponse {
 pub struct ServiceDefinition {
     pub id: String,
     pub methods: BTreeMap<String, MethodDescription>,
+    #[serde(default)]
     pub events: BTreeMap<String, EventDescription>,
     #[serde(rename = "service")]
     pub description: IoTScapeServiceDescription,
 }
 
+impl ServiceDefinition {
+    pub fn new(id: &str, description: IoTScapeServiceDescription) -> Self {
+        Self {
+            id: id.to_owned(),
+            methods: BTreeMap::new(),
+            events: BTreeMap::new(),
+            description,
+        }
+    }
+
+    /// Add a method to the definition
+    pub fn with_method(mut self, name: &str, method: MethodDescription) -> Self {
+        self.methods.insert(name.to_owned(), method);
+        self
+    }
+
+    /// Add an event type to the definition
+    pub fn with_event(mut self, name: &str, params: &[&str]) -> Self {
+        self.events.insert(name.to_owned(), EventDescription {
+            params: params.iter().map(|p| (*p).to_owned()).collect(),
+        });
+        self
+    }
+
+    /// Check a request against the method it calls, coercing its params to the declared types
+    /// 
+    /// Built-in functions (`heartbeat` and names starting with `_`) are always accepted.
+    pub fn validate_request(&self, request: &mut Request) -> Result<(), String> {
+        if request.function == "heartbeat" || request.function.starts_with('_') {
+            return Ok(());
+        }
+
+        match self.methods.get(&request.function) {
+            Some(method) => {
+                request.params = method.validate_params(&request.params)?;
+                Ok(())
+            }
+            None => Err(format!("Unknown method {}", request.function)),
+        }
+    }
+
+    /// Serialize the definition in the announce format, keyed by the service name
+    pub fn announcement(&self, name: &str) -> String {
+        serde_json::to_string(&BTreeMap::from([(name, self)])).unwrap()
+    }
+
+    /// Copy of the definition with methods, events and optional meta-data removed
+    pub fn lite(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            methods: BTreeMap::new(),
+            events: BTreeMap::new(),
+            description: IoTScapeServiceDescription {
+                version: self.description.version.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 /// Service meta-data for an IoTScape Service
-#[allow(non_snake_case)]
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct IoTScapeServiceDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
-    pub externalDocumentation: Option<String>,
-    pub termsOfService: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_documentation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terms_of_service: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub contact: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub license: Option<String>,
     pub version: String,
 }
@@ -105,55 +301,490 @@ pub struct IoTScapeServiceDescription {
 /// Describes a method belonging to an IoTScape service
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MethodDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub documentation: Option<String>,
+    #[serde(default)]
     pub params: Vec<MethodParam>,
     pub returns: MethodReturns,
+    /// Time handlers get to answer before the client is sent a timeout error
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+impl MethodDescription {
+    pub fn new(documentation: &str) -> Self {
+        Self {
+            documentation: Some(documentation.to_owned()),
+            params: Vec::new(),
+            returns: MethodReturns {
+                documentation: None,
+                r#type: Vec::new(),
+            },
+            timeout: None,
+        }
+    }
+
+    /// Add a required parameter
+    pub fn with_param(mut self, name: &str, r#type: IoTScapeType, documentation: &str) -> Self {
+        self.params.push(MethodParam {
+            name: name.to_owned(),
+            documentation: Some(documentation.to_owned()),
+            r#type,
+            optional: false,
+        });
+        self
+    }
+
+    /// Add an optional parameter
+    pub fn with_optional_param(mut self, name: &str, r#type: IoTScapeType, documentation: &str) -> Self {
+        self.params.push(MethodParam {
+            name: name.to_owned(),
+            documentation: Some(documentation.to_owned()),
+            r#type,
+            optional: true,
+        });
+        self
+    }
+
+    /// Add a return value type
+    pub fn with_return(mut self, r#type: IoTScapeType) -> Self {
+        self.returns.r#type.push(r#type);
+        self
+    }
+
+    /// Set the time handlers get to answer before the client is sent a timeout error
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the documentation of the return value
+    pub fn with_return_documentation(mut self, documentation: &str) -> Self {
+        self.returns.documentation = Some(documentation.to_owned());
+        self
+    }
+
+    /// Check params against the declared parameters, returning them coerced to the declared types
+    pub fn validate_params(&self, params: &[Value]) -> Result<Vec<Value>, String> {
+        if params.len() > self.params.len() {
+            return Err(format!("Expected at most {} parameters, got {}", self.params.len(), params.len()));
+        }
+
+        let mut coerced = Vec::with_capacity(params.len());
+        for (i, param) in self.params.iter().enumerate() {
+            match params.get(i) {
+                None | Some(Value::Null) => {
+                    if !param.optional {
+                        return Err(format!("Missing required parameter {}", param.name));
+                    }
+
+                    if i < params.len() {
+                        coerced.push(Value::Null);
+                    }
+                }
+                // Clients send empty strings for blank optional inputs
+                Some(Value::String(s)) if s.is_empty() && param.optional => coerced.push(Value::Null),
+                Some(value) => {
+                    coerced.push(param.r#type.coerce(value).map_err(|e| format!("Invalid parameter {}: {}", param.name, e))?);
+                }
+            }
+        }
+
+        Ok(coerced)
+    }
 }
 
 /// Describes a parameter of a method in an IoTScape service
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MethodParam {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub documentation: Option<String>,
-    pub r#type: String,
+    pub r#type: IoTScapeType,
+    #[serde(default)]
     pub optional: bool,
 }
 
 /// Describes a return value of a method in an IoTScape service
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MethodReturns {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub documentation: Option<String>,
-    pub r#type: Vec<String>,
+    pub r#type: Vec<IoTScapeType>,
 }
 
 /// Describes an event type in an IoTScape service
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EventDescription {
+    #[serde(default)]
     pub params: Vec<String>,
 }
 
+/// Default size in bytes above which responses are sent over HTTP, if an endpoint is set
+pub const DEFAULT_MAX_UDP_SIZE: usize = 8 * 1024;
+
+/// How a service gets announces and responses to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliveryPolicy {
+    /// UDP, switching to HTTP for messages larger than `max_udp_size` or when sending over UDP
+    /// fails, if `http` is set
+    #[default]
+    UdpFirst,
+    /// Only HTTP, for networks where UDP to the server is unreliable or blocked
+    HttpOnly,
+    /// Only UDP, even for large messages
+    UdpOnly,
+}
+
+/// The path a message took to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Udp,
+    Http,
+    /// Over the WebSocket of a `WsService` or `WsServiceAsync`
+    WebSocket,
+}
+
+/// Kinds of messages sent to the server, which go to different HTTP URLs
+#[derive(Debug, Clone, Copy)]
+enum Outgoing {
+    Announce,
+    Response,
+}
+
+impl Outgoing {
+    /// Whether the feature for sending these over HTTP is enabled
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    fn http_enabled(self) -> bool {
+        match self {
+            Outgoing::Announce => cfg!(feature = "http_announce"),
+            Outgoing::Response => cfg!(feature = "http_response"),
+        }
+    }
+}
+
+/// Error for messages sent over HTTP without the feature for them enabled
+const HTTP_DISABLED_ERROR: &str = "HTTP is not enabled for this message";
+
+/// Counters of incoming messages dropped or rejected by a service
+#[derive(Debug, Default)]
+pub struct ServiceStats {
+    /// Requests dropped for not being signed
+    pub unsigned: AtomicU64,
+    /// Requests dropped for having an invalid signature
+    pub bad_signature: AtomicU64,
+    /// Signed requests dropped for being stale or sent before
+    pub replayed: AtomicU64,
+    /// Packets dropped for coming from an address other than the server or allowed sources
+    pub bad_source: AtomicU64,
+    /// Requests answered with an error for exceeding the rate limit
+    pub rate_limited: AtomicU64,
+    /// Requests answered with an error for being rejected by access control
+    pub access_denied: AtomicU64,
+    /// Retransmitted requests answered from the response cache or ignored
+    pub duplicates: AtomicU64,
+    /// Requests answered with a timeout error for not being handled in time
+    pub timed_out: AtomicU64,
+}
+
+/// Error sent to clients when a handler does not answer within the method's timeout
+pub const TIMEOUT_ERROR: &str = "Request timed out";
+
+/// Error sent to clients whose requests were not handled before the service shut down
+pub const SHUTDOWN_ERROR: &str = "Service shutting down";
+
+/// Event telling the server the device is going away
+///
+/// This is a custom event of this crate, not part of the IoTScape protocol. The NetsBlox server
+/// handles it like any other event the service sends, so it only matters to servers and clients
+/// that look for it.
+pub const DISCONNECT_EVENT: &str = "_disconnect";
+
+/// Largest UDP payload, the size of receive buffers
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Delay before retrying a message that failed to send while shutting down
+#[cfg(feature = "std")]
+const SHUTDOWN_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Check a request against the access control rules, if there are any
+fn check_access(access_control: Option<&AccessControl>, request: &Request, stats: &ServiceStats) -> Result<(), String> {
+    match access_control {
+        Some(access_control) if !request.function.starts_with('_') && !access_control.authorize(request) => {
+            stats.access_denied.fetch_add(1, Ordering::Relaxed);
+            log::warn!("Denied call to {} from client {:?}", request.function, request.client_id);
+            Err("Access denied".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Take a token for a request from the rate limiter, if there is one
+#[cfg(feature = "std")]
+fn check_rate_limit(rate_limiter: Option<&RateLimiter>, request: &Request, stats: &ServiceStats) -> Result<(), String> {
+    match rate_limiter {
+        Some(limiter) if !request.function.starts_with('_') && !limiter.check(request) => {
+            stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+            Err("Rate limited".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check the sender of a packet against the server's address and port, and the IPs of allowed
+/// sources
+fn is_allowed_source(from: SocketAddr, server: SocketAddr, allowed_sources: &[IpAddr], stats: &ServiceStats) -> bool {
+    let ip = canonical_ip(from.ip());
+    if is_same_addr(from, server) || allowed_sources.iter().any(|a| canonical_ip(*a) == ip) {
+        return true;
+    }
+
+    stats.bad_source.fetch_add(1, Ordering::Relaxed);
+    log::warn!("Dropping packet from unexpected source {}", from);
+    false
+}
+
+/// Whether two addresses are the same, matching IPv4-mapped IPv6 addresses to IPv4 ones
+fn is_same_addr(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && canonical_ip(a.ip()) == canonical_ip(b.ip())
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4, so both forms of an address match
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    #[cfg(feature = "std")]
+    return ip.to_canonical();
+
+    #[cfg(not(feature = "std"))]
+    ip
+}
+
+/// Parse a datagram into a request, checking its signature if a signer is set
+#[cfg_attr(not(feature = "signing"), allow(unused_variables))]
+fn parse_request<'a>(
+    content: &'a [u8],
+    #[cfg(feature = "signing")] signer: Option<&Signer>,
+    stats: &ServiceStats,
+) -> Option<RequestRef<'a>> {
+    #[cfg(feature = "signing")]
+    if let Some(signer) = signer {
+        if let Err(e) = signer.verify(content) {
+            match e {
+                SignatureError::Missing => &stats.unsigned,
+                SignatureError::Invalid => &stats.bad_signature,
+                SignatureError::Replayed => &stats.replayed,
+                SignatureError::Malformed => {
+                    error!("Error parsing request: {}", e);
+                    return None;
+                }
+            }
+            .fetch_add(1, Ordering::Relaxed);
+            log::warn!("Dropping request: {}", e);
+            return None;
+        }
+    }
+
+    match RequestRef::parse(content) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            error!("Error parsing request: {}", e);
+            None
+        }
+    }
+}
+
+/// Serialize a message as JSON into a buffer, replacing its contents
+fn write_json<T: Serialize>(buf: &mut Vec<u8>, message: &T) {
+    buf.clear();
+
+    #[cfg(feature = "std")]
+    serde_json::to_writer(&mut *buf, message).unwrap();
+
+    #[cfg(not(feature = "std"))]
+    buf.extend_from_slice(serde_json::to_string(message).unwrap().as_bytes());
+}
+
+/// Build an error response to a request
+fn error_response(id: &str, request: &Request, error: String) -> Response {
+    Response {
+        id: id.to_owned(),
+        request: request.id.clone(),
+        service: request.service.clone(),
+        response: None,
+        event: None,
+        error: Some(error),
+    }
+}
+
+/// Keep the session of a request's client alive
+#[cfg(feature = "std")]
+fn touch_session(sessions: Option<&SessionStore>, request: &Request) {
+    if let (Some(sessions), Some(client_id)) = (sessions, &request.client_id) {
+        sessions.touch(client_id);
+    }
+}
+
+/// Timeouts of the methods in a definition that have one
+#[cfg(feature = "async")]
+fn method_timeouts(definition: &ServiceDefinition) -> BTreeMap<String, Duration> {
+    definition
+        .methods
+        .iter()
+        .filter_map(|(name, method)| Some((name.clone(), method.timeout?)))
+        .collect()
+}
+
+/// Messages to send on shutdown: errors for requests that will not be handled, then the disconnect event if wanted
+#[cfg(feature = "std")]
+fn shutdown_messages(
+    definition: &ServiceDefinition,
+    name: &str,
+    call_id: u64,
+    unhandled: impl IntoIterator<Item = Request>,
+    deadlines: &Deadlines,
+    notify_server: bool,
+) -> Vec<Response> {
+    let mut messages: Vec<Response> = unhandled
+        .into_iter()
+        .filter(|request| deadlines.finish(&request.id))
+        .map(|request| error_response(&definition.id, &request, SHUTDOWN_ERROR.to_owned()))
+        .collect();
+
+    if notify_server {
+        messages.push(Response {
+            id: definition.id.clone(),
+            request: format!("{}", call_id),
+            service: name.to_owned(),
+            response: None,
+            event: Some(EventResponse {
+                r#type: DISCONNECT_EVENT.to_owned(),
+                args: BTreeMap::new(),
+            }),
+            error: None,
+        });
+    }
+
+    messages
+}
+
+/// Result of draining the send queue on shutdown
+#[cfg(feature = "std")]
+fn drain_result(unsent: usize) -> Result<(), String> {
+    match unsent {
+        0 => Ok(()),
+        n => Err(format!("{} messages not sent before the timeout", n)),
+    }
+}
+
+/// Wait for a future for at most `duration`, returning `None` if it took longer
+///
+/// Uses `futures-timer` so it works the same on any runtime.
+#[cfg(feature = "async")]
+async fn with_timeout<F: core::future::Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let future = core::pin::pin!(future);
+    match futures::future::select(future, futures_timer::Delay::new(duration)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Encrypt the values and event arguments of a response
+#[cfg(feature = "security")]
+fn encrypt_response(security: &Security, response: &mut Response) {
+    if let Some(values) = &mut response.response {
+        security.encrypt_values(values);
+    }
+
+    if let Some(event) = &mut response.event {
+        if security.key().is_some() {
+            event.args.values_mut().for_each(|v| *v = security.encrypt(v));
+        }
+    }
+}
+
 /// An IoTScape service and socket setup to send/receive messages
 #[cfg(not(feature = "std"))]
 pub struct IoTScapeService<SocketType: SocketTrait> {
+    /// Changes made directly are announced after calling `definition_changed`
     pub definition: ServiceDefinition,
+    cached_definition: Option<String>,
+    /// Announce the definition again whenever it changes
+    pub auto_announce: bool,
     pub name: String,
     server: SocketAddr,
     socket: SocketType,
     pub next_msg_id: u64,
     pub rx_queue: VecDeque<Request>,
     pub tx_queue: VecDeque<Response>,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+    /// Check incoming requests against the definition, answering invalid ones with an error
+    pub validate_requests: bool,
+    /// How announces and responses get to the server
+    pub delivery: DeliveryPolicy,
+    /// Messages larger than this are sent over HTTP instead of UDP under
+    /// `DeliveryPolicy::UdpFirst`, if `http` is set and the socket is a UDP one
+    pub max_udp_size: usize,
+    /// Announces and responses over HTTP, which fail if not set
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    pub http: Option<HttpClient>,
+    /// Key storage and encryption, messages are sent unencrypted if not set
+    #[cfg(feature = "security")]
+    pub security: Option<Security>,
+    /// Signs responses and drops requests without a valid signature, if set
+    #[cfg(feature = "signing")]
+    pub signer: Option<Signer>,
+    /// IPs besides the server's address that requests are accepted from, on any port
+    pub allowed_sources: Vec<IpAddr>,
+    /// Rejects calls from clients not allowed to make them, with an error response
+    pub access_control: Option<AccessControl>,
+    pub stats: ServiceStats,
 }
 
 #[cfg(feature = "std")]
 pub struct IoTScapeService<SocketType: SocketTrait = StdUdpSocket> {
+    /// Changes made directly are announced after calling `definition_changed`
     pub definition: ServiceDefinition,
     cached_definition: Option<String>,
+    /// Announce the definition again whenever it changes
+    pub auto_announce: bool,
     pub name: String,
     server: SocketAddr,
     socket: SocketType,
     pub next_msg_id: u64,
     pub rx_queue: VecDeque<Request>,
     pub tx_queue: VecDeque<Response>,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+    /// Check incoming requests against the definition, answering invalid ones with an error
+    pub validate_requests: bool,
+    /// How announces and responses get to the server
+    pub delivery: DeliveryPolicy,
+    /// Messages larger than this are sent over HTTP instead of UDP under
+    /// `DeliveryPolicy::UdpFirst`, if `http` is set and the socket is a UDP one
+    pub max_udp_size: usize,
+    /// Announces and responses over HTTP, which fail if not set
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    pub http: Option<HttpClient>,
+    /// Key storage and encryption, messages are sent unencrypted if not set
+    #[cfg(feature = "security")]
+    pub security: Option<Security>,
+    /// Signs responses and drops requests without a valid signature, if set
+    #[cfg(feature = "signing")]
+    pub signer: Option<Signer>,
+    /// IPs besides the server's address that requests are accepted from, on any port
+    pub allowed_sources: Vec<IpAddr>,
+    /// Rejects calls from clients not allowed to make them, with an error response
+    pub access_control: Option<AccessControl>,
+    /// Answers clients calling too often with an error instead of queueing their requests
+    pub rate_limiter: Option<RateLimiter>,
+    /// Answers retransmitted requests with the cached response instead of handling them again
+    pub response_cache: Option<ResponseCache>,
+    /// State kept for each client between requests, for handlers to use
+    pub sessions: Option<SessionStore>,
+    /// Looks the server up again and fails over to others, the server address stays fixed if not set
+    pub resolver: Option<ServerResolver>,
+    deadlines: Deadlines,
+    pub stats: ServiceStats,
 }
 
 #[cfg(feature = "std")]
@@ -161,24 +792,19 @@ pub type IoTScapeServiceUdp = IoTScapeService<StdUdpSocket>;
 
 impl<SocketType: SocketTrait> IoTScapeService<SocketType> {
 
+    /// Send the service description to the announce URL of `http`
     #[cfg(feature = "http_announce")]
-    pub fn announce_http(&mut self, endpoint: &str) -> Result<reqwest::blocking::Response, reqwest::Error> {
-        trace!("Announcing {} to {}", self.get_definition(), endpoint);
-
-        reqwest::blocking::Client::new().post(endpoint)
-            .body(self.get_definition())
-            .header("Content-Type", "application/json")
-            .send()
+    pub fn announce_http(&mut self) -> Result<HttpResponse, String> {
+        let definition = self.get_definition();
+        let http = self.http.as_ref().ok_or(NO_HTTP_ERROR)?;
+        trace!("Announcing {} to {}", definition, http.config().announce_url());
+        http.announce(definition.into_bytes())
     }
 
     fn get_definition(&mut self) -> String {
         // Serialize definition if not already cached
         if self.cached_definition.is_none() {
-            self.cached_definition = Some(serde_json::to_string(&BTreeMap::from([(
-                self.name.to_owned(),
-                &self.definition,
-            )]))
-            .unwrap());
+            self.cached_definition = Some(self.definition.announcement(&self.name));
         }
 
         self.cached_definition.clone().unwrap()
@@ -190,50 +816,156 @@ impl<SocketType: SocketTrait> IoTScapeService<SocketType> {
             SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
         ];
         let socket = SocketType::bind(&addrs[..]).unwrap();
+        Self::with_socket(name, definition, server, socket)
+    }
+
+    /// Create a service for the server in a config, also sending over HTTP to its URLs if an
+    /// HTTP transport is enabled
+    #[cfg(feature = "std")]
+    pub fn from_config(name: &str, definition: ServiceDefinition, config: &IoTScapeConfig) -> Result<Self, String> {
+        #[allow(unused_mut)]
+        let mut service = Self::new(name, definition, config.server_addr()?);
+        service.resolver = Some(config.resolver());
+        #[cfg(all(any(feature = "http_announce", feature = "http_response"), any(feature = "reqwest", feature = "ureq")))]
+        {
+            service.http = Some(HttpClient::new(config.http_config()));
+        }
+        Ok(service)
+    }
+
+    /// Create a service using an already set up socket
+    pub fn with_socket(name: &str, definition: ServiceDefinition, server: SocketAddr, socket: SocketType) -> Self {
         Self {
             name: name.to_owned(),
             definition,
             cached_definition: None,
+            auto_announce: false,
             socket,
             server,
             rx_queue: VecDeque::<Request>::new(),
             tx_queue: VecDeque::<Response>::new(),
+            recv_buf: alloc::vec![0u8; MAX_DATAGRAM_SIZE],
+            send_buf: Vec::new(),
             next_msg_id: 0,
+            validate_requests: false,
+            delivery: DeliveryPolicy::default(),
+            max_udp_size: DEFAULT_MAX_UDP_SIZE,
+            #[cfg(any(feature = "http_announce", feature = "http_response"))]
+            http: None,
+            #[cfg(feature = "security")]
+            security: None,
+            #[cfg(feature = "signing")]
+            signer: None,
+            allowed_sources: Vec::new(),
+            access_control: None,
+            #[cfg(feature = "std")]
+            rate_limiter: None,
+            #[cfg(feature = "std")]
+            response_cache: None,
+            #[cfg(feature = "std")]
+            sessions: None,
+            #[cfg(feature = "std")]
+            resolver: None,
+            #[cfg(feature = "std")]
+            deadlines: Deadlines::default(),
+            stats: ServiceStats::default(),
         }
     }
 
-    /// Send the service description to the server
-    pub fn announce(&mut self) -> Result<usize, String> {
+    /// The socket used to talk to the server
+    pub fn socket(&self) -> &SocketType {
+        &self.socket
+    }
+
+    /// Address of the server the service currently talks to
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Send the service description to the server the way `delivery` says
+    pub fn announce(&mut self) -> Result<Delivery, String> {
         let definition_string = self.get_definition();
 
         // Send to server
         trace!("Announcing {:?}", definition_string);
-        self.socket.send_to(definition_string.as_bytes(), self.server)
+        self.deliver(definition_string.as_bytes(), Outgoing::Announce)
+    }
+
+    /// Send a datagram to the server, counting failures against it if `resolver` is set
+    fn send_to_server(&self, buf: &[u8]) -> Result<usize, String> {
+        let r = self.socket.send_to(buf, self.server);
+        #[cfg(feature = "std")]
+        if let (Err(_), Some(resolver)) = (&r, &self.resolver) {
+            resolver.report_failure();
+        }
+        r
+    }
+
+    /// Start looking the server up again if due, announcing to the new address once a lookup
+    /// finds it changed
+    #[cfg(feature = "std")]
+    fn check_server(&mut self) {
+        let Some(resolver) = &self.resolver else {
+            return;
+        };
+        let Some(addr) = resolver.check() else {
+            return;
+        };
+
+        if addr != self.server {
+            log::info!("Server {} is now at {}", resolver.current(), addr);
+            self.server = addr;
+            if let Err(e) = self.announce() {
+                error!("Error announcing to {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Add or replace a method
+    pub fn add_method(&mut self, name: &str, method: MethodDescription) -> Result<(), String> {
+        self.definition.methods.insert(name.to_owned(), method);
+        self.definition_changed()
+    }
+
+    /// Remove a method, returning it if it existed
+    pub fn remove_method(&mut self, name: &str) -> Result<Option<MethodDescription>, String> {
+        let removed = self.definition.methods.remove(name);
+        if removed.is_some() {
+            self.definition_changed()?;
+        }
+        Ok(removed)
+    }
+
+    /// Replace the description of the service
+    pub fn update_description(&mut self, description: IoTScapeServiceDescription) -> Result<(), String> {
+        self.definition.description = description;
+        self.definition_changed()
+    }
+
+    /// Drop the serialized definition so the next announce sends the current one, announcing
+    /// right away if `auto_announce` is set
+    pub fn definition_changed(&mut self) -> Result<(), String> {
+        self.cached_definition = None;
+        if self.auto_announce {
+            self.announce()?;
+        }
+        Ok(())
     }
 
     /// Announce without full definition
-    pub fn announce_lite(&self) -> Result<usize, String> {
-        let mut definition_lite = self.definition.clone();
-        definition_lite.methods = BTreeMap::new();
-        definition_lite.events = BTreeMap::new();
-        definition_lite.description.description = None;
-        definition_lite.description.externalDocumentation = None;
-        definition_lite.description.termsOfService = None;
-        definition_lite.description.contact = None;
-        definition_lite.description.license = None;        
-
-        let definition_string = serde_json::to_string(&BTreeMap::from([(
-            self.name.to_owned(),
-            &definition_lite,
-        )])).unwrap();
-        
+    pub fn announce_lite(&self) -> Result<Delivery, String> {
+        let definition_string = self.definition.lite().announcement(&self.name);
+
         // Send to server
         trace!("Announcing {:?}", definition_string);
-        self.socket.send_to(definition_string.as_bytes(), self.server)        
+        self.deliver(definition_string.as_bytes(), Outgoing::Announce)
     }
     
     /// Handle rx/tx
     pub fn poll(&mut self, timeout: Option<Duration>) {
+        #[cfg(feature = "std")]
+        self.check_server();
+
         self.socket
             .set_read_timeout(timeout.or(Some(Duration::from_millis(15))))
             .unwrap();
@@ -242,109 +974,388 @@ impl<SocketType: SocketTrait> IoTScapeService<SocketType> {
             .unwrap();
 
         // Get incoming messages
-        loop {
-            let mut buf = [0u8; 65_535];
-            match self.socket.recv(&mut buf) {
-                Ok(size) => {
-                    let content = &buf[..size];
-
-                    match serde_json::from_slice::<Request>(content) {
-                        Ok(msg) => {
-                            // Handle heartbeat immediately
-                            if msg.function == "heartbeat" {
-                                self.send_response(Response {
-                                    id: self.definition.id.clone(),
-                                    request: msg.id,
-                                    service: msg.service,
-                                    response: Some(alloc::vec![]),
-                                    event: None,
-                                    error: None,
-                                }).unwrap();
-                                self.next_msg_id += 1;
-                            } else {
-                                self.rx_queue.push_back(msg);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error parsing request: {}", e);
-                        }
-                    }
-                }
-                Err(_) => {
-                    break;
-                }
+        let mut buf = core::mem::take(&mut self.recv_buf);
+        while let Ok((size, from)) = self.socket.recv_from(&mut buf) {
+            if !is_allowed_source(from, self.server, &self.allowed_sources, &self.stats) {
+                continue;
+            }
+
+            #[cfg(feature = "std")]
+            if let (true, Some(resolver)) = (is_same_addr(from, self.server), &self.resolver) {
+                resolver.heard_from_server();
+            }
+
+            let content = &buf[..size];
+
+            if let Some(msg) = parse_request(
+                content,
+                #[cfg(feature = "signing")]
+                self.signer.as_ref(),
+                &self.stats,
+            ) {
+                self.handle_request(msg);
             }
         }
+        self.recv_buf = buf;
+
+        #[cfg(feature = "std")]
+        self.expire_requests();
+
+        #[cfg(feature = "std")]
+        if let Some(sessions) = &self.sessions {
+            sessions.expire();
+        }
 
         // Send queued messages
         while !self.tx_queue.is_empty() {
             let next_msg = self.tx_queue.pop_front().unwrap();
+
+            #[cfg(feature = "std")]
+            if next_msg.event.is_none() && !self.deadlines.finish(&next_msg.request) {
+                trace!("Dropping response to timed out request {}", next_msg.request);
+                continue;
+            }
+
             if let Err(e) = self.send_response(next_msg) {
                 error!("Error sending response: {}", e);
             }
         }
     }
 
-    /// Create a response to an Request and enqueue it for sending
-    pub fn enqueue_response_to(
-        &mut self,
-        request: Request,
-        params: Result<Vec<Value>, String>,
-    ) -> Result<usize, String> {
-        let mut response = None;
-        let mut error = None;
+    /// Answer built-in requests and queue the rest for handlers
+    fn handle_request(&mut self, msg: RequestRef<'_>) {
+        // Handle heartbeat immediately
+        if msg.function == "heartbeat" {
+            let sent = self.send_response(Response {
+                id: self.definition.id.clone(),
+                request: msg.id.into_owned(),
+                service: msg.service.into_owned(),
+                response: Some(alloc::vec![]),
+                event: None,
+                error: None,
+            });
+            if let Err(e) = sent {
+                error!("Error answering heartbeat: {}", e);
+            }
+            self.next_msg_id += 1;
+            return;
+        }
 
-        match params {
-            Ok(p) => {
-                response = Some(p);
+        #[cfg(feature = "std")]
+        if let Some(cache) = &self.response_cache {
+            match cache.check(&msg.id) {
+                Seen::New => {}
+                Seen::Pending => {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Seen::Answered(response) => {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = self.send_response(response) {
+                        error!("Error sending response: {}", e);
+                    }
+                    return;
+                }
             }
+        }
+
+        #[cfg(feature = "std")]
+        let id = msg.id.clone();
+        let mut msg = match msg.into_owned() {
+            Ok(msg) => msg,
             Err(e) => {
-                error = Some(e);
+                error!("Error parsing request: {}", e);
+                #[cfg(feature = "std")]
+                if let Some(cache) = &self.response_cache {
+                    cache.forget(&id);
+                }
+                return;
             }
-        }
+        };
 
-        self.send_response(Response {
-            id: self.definition.id.clone(),
-            request: request.id.to_owned(),
-            service: request.service,
-            response,
-            event: None,
-            error,
-        }).and_then(|r| { self.next_msg_id += 1; Ok(r) })
-    }
+        #[cfg(feature = "security")]
+        if let Some(security) = &mut self.security {
+            if msg.function == security::REQUESTED_KEY_FUNCTION {
+                if let Err(e) = security.accept_key(&msg.params) {
+                    error!("Error reading key: {}", e);
+                }
+            } else {
+                security.decrypt_values(&mut msg.params);
+            }
+        }
 
-    /// Set an event message to be sent
-    pub fn send_event(&mut self, call_id: &str, event_type: &str, args: BTreeMap<String, String>) -> Result<usize, String> {
+        if let Err(e) = self.check_request(&mut msg) {
+            let response = error_response(&self.definition.id, &msg, e);
+            if let Err(e) = self.send_response(response) {
+                error!("Error sending response: {}", e);
+            }
+        } else {
+            #[cfg(feature = "std")]
+            if let Some(timeout) = self.definition.methods.get(&msg.function).and_then(|m| m.timeout) {
+                self.deadlines.start(&msg, timeout);
+            }
+
+            #[cfg(feature = "std")]
+            touch_session(self.sessions.as_ref(), &msg);
+
+            self.rx_queue.push_back(msg);
+        }
+    }
+
+    /// Run the checks enabled on this service against an incoming request
+    fn check_request(&self, request: &mut Request) -> Result<(), String> {
+        check_access(self.access_control.as_ref(), request, &self.stats)?;
+
+        #[cfg(feature = "std")]
+        check_rate_limit(self.rate_limiter.as_ref(), request, &self.stats)?;
+
+        if self.validate_requests {
+            self.definition.validate_request(request)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ask the server for a new key, which arrives later as a `_requestedKey` request
+    #[cfg(feature = "security")]
+    pub fn request_key(&mut self) -> Result<Delivery, String> {
+        let call_id = format!("{}", self.next_msg_id);
+        let r = self.send_event(&call_id, security::REQUEST_KEY_EVENT, BTreeMap::new());
+        self.next_msg_id += 1;
+        self.security.get_or_insert_with(Security::default).set_requested();
+        r
+    }
+
+    /// Clear the encryption settings on the server and forget the current key
+    #[cfg(feature = "security")]
+    pub fn reset_key(&mut self) -> Result<Delivery, String> {
+        let call_id = format!("{}", self.next_msg_id);
+        let r = self.send_event(&call_id, security::RESET_EVENT, BTreeMap::new());
+        self.next_msg_id += 1;
+        if let Some(security) = &mut self.security {
+            security.reset();
+        }
+        #[cfg(feature = "std")]
+        if let Some(sessions) = &self.sessions {
+            sessions.clear();
+        }
+        r
+    }
+
+    /// Send timeout errors for requests past their method's timeout
+    #[cfg(feature = "std")]
+    fn expire_requests(&mut self) {
+        for (request, service) in self.deadlines.expire() {
+            self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+            let response = Response {
+                id: self.definition.id.clone(),
+                request,
+                service,
+                response: None,
+                event: None,
+                error: Some(TIMEOUT_ERROR.to_owned()),
+            };
+            if let Err(e) = self.send_response(response) {
+                error!("Error sending response: {}", e);
+            }
+        }
+    }
+
+    /// Stop the service and close its socket
+    ///
+    /// Requests not yet handled are answered with an error, then queued responses and events are
+    /// sent until the timeout passes. If `notify_server` is set, a [`DISCONNECT_EVENT`] is sent last
+    /// to tell the server the device is going away.
+    #[cfg(feature = "std")]
+    pub fn shutdown(mut self, timeout: Duration, notify_server: bool) -> Result<(), String> {
+        let deadline = std::time::Instant::now() + timeout;
+        if let Some(sessions) = &self.sessions {
+            sessions.clear();
+        }
+
+        let unhandled = core::mem::take(&mut self.rx_queue);
+        let messages = shutdown_messages(&self.definition, &self.name, self.next_msg_id, unhandled, &self.deadlines, notify_server);
+        self.tx_queue.extend(messages);
+
+        while let Some(next_msg) = self.tx_queue.pop_front() {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                self.tx_queue.push_front(next_msg);
+                break;
+            }
+
+            if next_msg.event.is_none() && !self.deadlines.finish(&next_msg.request) {
+                continue;
+            }
+
+            self.socket.set_write_timeout(Some(remaining)).ok();
+            if let Err(e) = self.send_response(next_msg.clone()) {
+                error!("Error sending response: {}", e);
+                self.tx_queue.push_front(next_msg);
+                std::thread::sleep(SHUTDOWN_RETRY_DELAY.min(remaining));
+            }
+        }
+
+        drain_result(self.tx_queue.len())
+    }
+
+    /// Create a response to an Request and enqueue it for sending
+    #[deprecated(note = "use `respond`, which reports how the response was sent")]
+    pub fn enqueue_response_to(
+        &mut self,
+        request: Request,
+        params: Result<Vec<Value>, String>,
+    ) -> Result<Delivery, String> {
+        self.respond(request, params)
+    }
+
+    /// Send a response to a request the way `delivery` says
+    pub fn respond(
+        &mut self,
+        request: Request,
+        params: Result<Vec<Value>, String>,
+    ) -> Result<Delivery, String> {
+        #[cfg(feature = "std")]
+        if !self.deadlines.finish(&request.id) {
+            return Err(TIMEOUT_ERROR.to_owned());
+        }
+
+        let mut response = None;
+        let mut error = None;
+
+        match params {
+            Ok(p) => {
+                response = Some(p);
+            }
+            Err(e) => {
+                error = Some(e);
+            }
+        }
+
+        self.send_response(Response {
+            id: self.definition.id.clone(),
+            request: request.id,
+            service: request.service,
+            response,
+            event: None,
+            error,
+        }).inspect(|_| { self.next_msg_id += 1; })
+    }
+
+    /// Set an event message to be sent
+    pub fn send_event(&mut self, call_id: &str, event_type: &str, args: BTreeMap<String, String>) -> Result<Delivery, String> {
         self.send_response(Response {
             id: self.definition.id.clone(),
             request: call_id.to_owned(),
             service: self.name.to_owned(),
             response: None,
             event: Some(EventResponse {
-                r#type: Some(event_type.to_owned()),
-                args: Some(args),
+                r#type: event_type.to_owned(),
+                args,
             }),
             error: None,
         })
     }
 
+    fn serialize_response(&self, response: &Response, buf: &mut Vec<u8>) {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer {
+            signer.sign_response(response, buf);
+            return;
+        }
+
+        write_json(buf, response);
+    }
+
     /// Sends an Response to ther server
-    fn send_response(&mut self, response: Response) -> Result<usize, String>{
-        let as_string = serde_json::to_string(&response).unwrap();
-        trace!("Sending response {:?}", as_string);
-        self.socket
-            .send_to(as_string.as_bytes(), self.server)
+    fn send_response(&mut self, #[allow(unused_mut)] mut response: Response) -> Result<Delivery, String>{
+        #[cfg(feature = "std")]
+        if let (Some(cache), None) = (&self.response_cache, &response.event) {
+            cache.store(&response);
+        }
+
+        #[cfg(feature = "security")]
+        if let Some(security) = &self.security {
+            encrypt_response(security, &mut response);
+        }
+
+        let mut buf = core::mem::take(&mut self.send_buf);
+        self.serialize_response(&response, &mut buf);
+        trace!("Sending response {:?}", String::from_utf8_lossy(&buf));
+        let r = self.deliver(&buf, Outgoing::Response);
+        self.send_buf = buf;
+        r
     }
 
+    /// Send a serialized message to the server the way `delivery` says
+    fn deliver(&self, buf: &[u8], kind: Outgoing) -> Result<Delivery, String> {
+        match self.delivery {
+            DeliveryPolicy::UdpOnly => self.send_to_server(buf).map(|_| self.socket.delivery()),
+            DeliveryPolicy::HttpOnly => self.send_http(buf, kind),
+            DeliveryPolicy::UdpFirst => {
+                // Large messages may not make it through UDP, other sockets take any size
+                let udp = self.socket.delivery() == Delivery::Udp;
+                if udp && buf.len() > self.max_udp_size && self.has_http(kind) {
+                    return self.send_http(buf, kind);
+                }
 
-    /// Create a response to an Request and enqueue it for sending
+                match self.send_to_server(buf) {
+                    Ok(_) => Ok(self.socket.delivery()),
+                    Err(e) if self.has_http(kind) => {
+                        log::warn!("Sending over UDP failed, using HTTP: {}", e);
+                        self.send_http(buf, kind)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Whether messages of a kind can be sent over HTTP
+    #[cfg_attr(not(any(feature = "http_announce", feature = "http_response")), allow(unused_variables))]
+    fn has_http(&self, kind: Outgoing) -> bool {
+        #[cfg(any(feature = "http_announce", feature = "http_response"))]
+        return kind.http_enabled() && self.http.is_some();
+
+        #[cfg(not(any(feature = "http_announce", feature = "http_response")))]
+        false
+    }
+
+    /// Send a serialized message to its URL in `http`
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    fn send_http(&self, buf: &[u8], kind: Outgoing) -> Result<Delivery, String> {
+        if !kind.http_enabled() {
+            return Err(HTTP_DISABLED_ERROR.to_owned());
+        }
+
+        let http = self.http.as_ref().ok_or(NO_HTTP_ERROR)?;
+        let response = match kind {
+            Outgoing::Announce => http.announce(buf.to_vec())?,
+            Outgoing::Response => http.respond(buf.to_vec())?,
+        };
+        if !response.is_success() {
+            return Err(format!("HTTP server answered with status {}", response.status));
+        }
+        Ok(Delivery::Http)
+    }
+
+    #[cfg(not(any(feature = "http_announce", feature = "http_response")))]
+    fn send_http(&self, _buf: &[u8], _kind: Outgoing) -> Result<Delivery, String> {
+        Err(HTTP_DISABLED_ERROR.to_owned())
+    }
+
+
+    /// Create a response to an Request and send it to the response URL of `http`
     #[cfg(feature = "http_response")]
     pub fn enqueue_response_to_http(
         &self, 
-        endpoint: &str,
         request: Request,
         params: Result<Vec<Value>, String>,
-    ) -> Result<reqwest::blocking::Response, reqwest::Error> {
+    ) -> Result<HttpResponse, String> {
+        // Stop the clock, the server drops a response arriving after the timeout error
+        #[cfg(feature = "std")]
+        self.deadlines.finish(&request.id);
+
         let mut response = None;
         let mut error = None;
 
@@ -357,9 +1368,9 @@ impl<SocketType: SocketTrait> IoTScapeService<SocketType> {
             }
         }
 
-        self.send_response_http(endpoint, Response {
+        self.send_response_http(Response {
             id: self.definition.id.clone(),
-            request: request.id.to_owned(),
+            request: request.id,
             service: request.service,
             response,
             event: None,
@@ -368,208 +1379,796 @@ impl<SocketType: SocketTrait> IoTScapeService<SocketType> {
     }
     
     #[cfg(feature = "http_response")]
-    fn send_response_http(&self, endpoint: &str, response: Response) -> Result<reqwest::blocking::Response, reqwest::Error> {
-        let client = reqwest::blocking::ClientBuilder::new().timeout(Duration::from_secs(5)).connect_timeout(Duration::from_secs(5)).build().unwrap();
+    fn send_response_http(&self, #[allow(unused_mut)] mut response: Response) -> Result<HttpResponse, String> {
+        let http = self.http.as_ref().ok_or(NO_HTTP_ERROR)?;
 
-        client.post(endpoint)
-            .body(serde_json::to_string(&response).unwrap())
-            .header("Content-Type", "application/json")
-            .send()
+        #[cfg(feature = "std")]
+        if let (Some(cache), None) = (&self.response_cache, &response.event) {
+            cache.store(&response);
+        }
+
+        #[cfg(feature = "security")]
+        if let Some(security) = &self.security {
+            encrypt_response(security, &mut response);
+        }
+
+        let mut buf = Vec::new();
+        self.serialize_response(&response, &mut buf);
+        http.respond(buf)
     }
 }
 
 
+/// Socket `IoTScapeServiceAsync` uses unless another is chosen, from the enabled runtime
 #[cfg(feature = "tokio")]
-pub struct IoTScapeServiceAsync<SocketType: SocketTraitAsync = TokioUdpSocket> {
-    pub definition: ServiceDefinition,
-    cached_definition: String,
+pub type DefaultSocketAsync = TokioUdpSocket;
+/// Socket `IoTScapeServiceAsync` uses unless another is chosen, from the enabled runtime
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub type DefaultSocketAsync = Async<StdUdpSocket>;
+
+#[cfg(feature = "async")]
+pub struct IoTScapeServiceAsync<SocketType: SocketTraitAsync = DefaultSocketAsync> {
+    /// Changes made directly are announced after calling `definition_changed`
+    pub definition: Mutex<ServiceDefinition>,
+    cached_definition: Mutex<String>,
+    /// Id from the definition, kept apart so responses don't wait on the definition
+    cached_id: Mutex<String>,
+    /// Timeouts of methods from the definition
+    cached_timeouts: Mutex<BTreeMap<String, Duration>>,
+    /// Announce the definition again whenever it changes
+    pub auto_announce: bool,
     pub name: String,
-    server: SocketAddr,
-    socket: Arc<SocketType>,
+    server: Mutex<SocketAddr>,
+    /// Released on shutdown
+    socket: Mutex<Option<Arc<SocketType>>>,
     pub next_msg_id: AtomicU64,
-    pub rx_queue: Arc<Mutex<VecDeque<Request>>>,
-    pub tx_queue: Arc<Mutex<VecDeque<Response>>>,
+    /// Requests for handlers, taken with `pop_request`
+    rx_queue: SegQueue<Request>,
+    /// Responses and events for `poll` to send, added with `queue_response`
+    tx_queue: SegQueue<Response>,
+    /// Check incoming requests against the definition, answering invalid ones with an error
+    pub validate_requests: bool,
+    /// How announces and responses get to the server
+    pub delivery: DeliveryPolicy,
+    /// Messages larger than this are sent over HTTP instead of UDP under
+    /// `DeliveryPolicy::UdpFirst`, if `http` is set and the socket is a UDP one
+    pub max_udp_size: usize,
+    /// Announces and responses over HTTP, which fail if not set
     #[cfg(any(feature = "http_announce", feature = "http_response"))]
-    pub client: reqwest::Client,
+    pub http: Option<HttpClientAsync>,
+    /// Key storage and encryption, messages are sent unencrypted if not set
+    #[cfg(feature = "security")]
+    pub security: Mutex<Option<Security>>,
+    /// Signs responses and drops requests without a valid signature, if set
+    #[cfg(feature = "signing")]
+    pub signer: Option<Signer>,
+    /// IPs besides the server's address that requests are accepted from, on any port
+    pub allowed_sources: Vec<IpAddr>,
+    /// Rejects calls from clients not allowed to make them, with an error response
+    pub access_control: Option<AccessControl>,
+    /// Answers clients calling too often with an error instead of queueing their requests
+    pub rate_limiter: Option<RateLimiter>,
+    /// Answers retransmitted requests with the cached response instead of handling them again
+    pub response_cache: Option<ResponseCache>,
+    /// State kept for each client between requests, for handlers to use
+    pub sessions: Option<SessionStore>,
+    /// Looks the server up again and fails over to others, the server address stays fixed if not set
+    pub resolver: Option<ServerResolver>,
+    deadlines: Deadlines,
+    shut_down: AtomicBool,
+    /// Closed on shutdown, which also wakes up `next_request`
+    response_tx: UnboundedSender<Response>,
+    response_rx: futures::lock::Mutex<UnboundedReceiver<Response>>,
+    buffers: BufferPool,
+    pub stats: ServiceStats,
 }
 
 #[cfg(feature = "tokio")]
 pub type IoTScapeServiceAsyncUdp = IoTScapeServiceAsync<TokioUdpSocket>;
 
-#[cfg(feature = "tokio")]
+#[cfg(feature = "smol")]
+pub type IoTScapeServiceAsyncSmol = IoTScapeServiceAsync<Async<StdUdpSocket>>;
+
+#[cfg(feature = "async")]
 impl<SocketType: SocketTraitAsync> IoTScapeServiceAsync<SocketType> {
     pub async fn new(name: &str, definition: ServiceDefinition, server: SocketAddr) -> Self {
         let addrs = [
             SocketAddr::from(([0, 0, 0, 0], 0)),
             SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
         ];
-        let socket = Arc::new(SocketType::bind(&addrs[0]).await.unwrap());
-        
+        let socket = SocketType::bind(&addrs[0]).await.unwrap();
+        Self::with_socket(name, definition, server, socket)
+    }
+
+    /// Create a service using an already set up socket
+    pub fn with_socket(name: &str, definition: ServiceDefinition, server: SocketAddr, socket: SocketType) -> Self {
         // Serialize definition now
-        let cached_definition = serde_json::to_string(&BTreeMap::from([(
-            name.to_owned(),
-            &definition,
-        )])).unwrap();
+        let cached_definition = definition.announcement(name);
+        let cached_id = definition.id.clone();
+        let cached_timeouts = method_timeouts(&definition);
+        let (response_tx, response_rx) = futures::channel::mpsc::unbounded();
 
         Self {
             name: name.to_owned(),
-            definition,
-            cached_definition,
-            socket,
-            server,
-            rx_queue: Arc::new(Mutex::new(VecDeque::<Request>::new())),
-            tx_queue: Arc::new(Mutex::new(VecDeque::<Response>::new())),
+            definition: Mutex::new(definition),
+            cached_definition: Mutex::new(cached_definition),
+            cached_id: Mutex::new(cached_id),
+            cached_timeouts: Mutex::new(cached_timeouts),
+            auto_announce: false,
+            socket: Mutex::new(Some(Arc::new(socket))),
+            server: Mutex::new(server),
+            rx_queue: SegQueue::new(),
+            tx_queue: SegQueue::new(),
             next_msg_id: AtomicU64::new(0),
+            validate_requests: false,
+            delivery: DeliveryPolicy::default(),
+            max_udp_size: DEFAULT_MAX_UDP_SIZE,
             #[cfg(any(feature = "http_announce", feature = "http_response"))]
-            client: reqwest::Client::new(),
+            http: None,
+            #[cfg(feature = "security")]
+            security: Mutex::new(None),
+            #[cfg(feature = "signing")]
+            signer: None,
+            allowed_sources: Vec::new(),
+            access_control: None,
+            #[cfg(feature = "std")]
+            rate_limiter: None,
+            #[cfg(feature = "std")]
+            response_cache: None,
+            #[cfg(feature = "std")]
+            sessions: None,
+            #[cfg(feature = "std")]
+            resolver: None,
+            #[cfg(feature = "std")]
+            deadlines: Deadlines::default(),
+            shut_down: AtomicBool::new(false),
+            response_tx,
+            response_rx: futures::lock::Mutex::new(response_rx),
+            buffers: BufferPool::default(),
+            stats: ServiceStats::default(),
         }
     }
 
-    /// Send the service description to the server
-    pub async fn announce(&self) -> Result<usize, std::io::Error> {
+    /// Create a service for the server in a config, also sending over HTTP to its URLs if an
+    /// HTTP transport is enabled
+    pub async fn from_config(name: &str, definition: ServiceDefinition, config: &IoTScapeConfig) -> Result<Self, std::io::Error> {
+        let server = config.server_addr().map_err(std::io::Error::other)?;
+        #[allow(unused_mut)]
+        let mut service = Self::new(name, definition, server).await;
+        service.resolver = Some(config.resolver());
+        #[cfg(all(any(feature = "http_announce", feature = "http_response"), any(feature = "reqwest", feature = "ureq")))]
+        {
+            service.http = Some(HttpClientAsync::new(config.http_config()));
+        }
+        Ok(service)
+    }
+
+    /// Send the service description to the server the way `delivery` says
+    pub async fn announce(&self) -> Result<Delivery, std::io::Error> {
+        let definition_string = self.cached_definition.lock().unwrap().clone();
+
         // Send to server
-        trace!("Announcing {:?}", self.cached_definition);
-        self.socket
-            .send_to(self.cached_definition.as_bytes(), self.server).await
+        trace!("Announcing {:?}", definition_string);
+        self.deliver(definition_string.as_bytes(), Outgoing::Announce).await
+    }
+
+    /// The socket used to talk to the server, `None` once the service is shut down
+    pub fn socket(&self) -> Option<Arc<SocketType>> {
+        self.socket.lock().unwrap().clone()
+    }
+
+    /// The socket, or an error once the service is shut down
+    fn open_socket(&self) -> Result<Arc<SocketType>, std::io::Error> {
+        self.socket().ok_or_else(|| std::io::Error::other(SHUTDOWN_ERROR))
+    }
+
+    /// Address of the server the service currently talks to
+    pub fn server(&self) -> SocketAddr {
+        *self.server.lock().unwrap()
     }
 
+    /// Send a datagram to the server, counting failures against it if `resolver` is set
+    async fn send_to_server(&self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let r = self.open_socket()?.send_to(buf, self.server()).await;
+        if let (Err(_), Some(resolver)) = (&r, &self.resolver) {
+            resolver.report_failure();
+        }
+        r
+    }
+
+    /// Look the server up again if due, announcing to the new address if it changed
+    async fn check_server(&self) {
+        let Some(resolver) = &self.resolver else {
+            return;
+        };
+        let Some(server) = resolver.next_lookup() else {
+            return;
+        };
+
+        if let Some(addr) = resolver.looked_up(resolve::resolve_async(server.clone()).await) {
+            let changed = core::mem::replace(&mut *self.server.lock().unwrap(), addr) != addr;
+            if changed {
+                log::info!("Server {} is now at {}", server, addr);
+                if let Err(e) = self.announce().await {
+                    error!("Error announcing to {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    /// Add or replace a method
+    pub async fn add_method(&self, name: &str, method: MethodDescription) -> Result<(), std::io::Error> {
+        self.definition.lock().unwrap().methods.insert(name.to_owned(), method);
+        self.definition_changed().await
+    }
+
+    /// Remove a method, returning it if it existed
+    pub async fn remove_method(&self, name: &str) -> Result<Option<MethodDescription>, std::io::Error> {
+        let removed = self.definition.lock().unwrap().methods.remove(name);
+        if removed.is_some() {
+            self.definition_changed().await?;
+        }
+        Ok(removed)
+    }
+
+    /// Replace the description of the service
+    pub async fn update_description(&self, description: IoTScapeServiceDescription) -> Result<(), std::io::Error> {
+        self.definition.lock().unwrap().description = description;
+        self.definition_changed().await
+    }
+
+    /// Serialize the definition again so announces send the current one, announcing right away
+    /// if `auto_announce` is set
+    pub async fn definition_changed(&self) -> Result<(), std::io::Error> {
+        {
+            let definition = self.definition.lock().unwrap();
+            *self.cached_definition.lock().unwrap() = definition.announcement(&self.name);
+            *self.cached_id.lock().unwrap() = definition.id.clone();
+            *self.cached_timeouts.lock().unwrap() = method_timeouts(&definition);
+        }
+        if self.auto_announce {
+            self.announce().await?;
+        }
+        Ok(())
+    }
+
+    /// Id of the device, sent with every response
+    fn device_id(&self) -> String {
+        self.cached_id.lock().unwrap().clone()
+    }
+
+    /// Time handlers get to answer a call to a method, if limited
+    fn method_timeout(&self, method: &str) -> Option<Duration> {
+        self.cached_timeouts.lock().unwrap().get(method).copied()
+    }
 
     /// Announce without full definition
-    pub async fn announce_lite(&self) -> Result<usize, std::io::Error> {
-        let mut definition_lite = self.definition.clone();
-        definition_lite.methods = BTreeMap::new();
-        definition_lite.events = BTreeMap::new();
-        definition_lite.description.description = None;
-        definition_lite.description.externalDocumentation = None;
-        definition_lite.description.termsOfService = None;
-        definition_lite.description.contact = None;
-        definition_lite.description.license = None;        
-
-        let definition_string = serde_json::to_string(&BTreeMap::from([(
-            self.name.to_owned(),
-            &definition_lite,
-        )])).unwrap();
-        
+    pub async fn announce_lite(&self) -> Result<Delivery, std::io::Error> {
+        let definition_string = self.definition.lock().unwrap().lite().announcement(&self.name);
+
         // Send to server
         trace!("Announcing {:?}", definition_string);
-        self.socket.send_to(definition_string.as_bytes(), self.server).await
+        self.deliver(definition_string.as_bytes(), Outgoing::Announce).await
     }
 
+    /// Send the service description to the announce URL of `http`
     #[cfg(feature = "http_announce")]
-    pub async fn announce_http(&self, endpoint: &str) -> Result<reqwest::Response, reqwest::Error> {
-        self.client.post(endpoint)
-            .body(self.cached_definition.to_owned())
-            .header("Content-Type", "application/json")
-            .send().await
+    pub async fn announce_http(&self) -> Result<HttpResponse, std::io::Error> {
+        let http = self.http.as_ref().ok_or_else(|| std::io::Error::other(NO_HTTP_ERROR))?;
+        let definition_string = self.cached_definition.lock().unwrap().clone();
+        trace!("Announcing {} to {}", definition_string, http.config().announce_url());
+        http.announce(definition_string.into_bytes()).await
     }
 
-    /// Handle rx/tx
+    /// Handle rx/tx, does nothing once the service is shut down
     pub async fn poll(&self) {
+        let Some(socket) = self.socket().filter(|_| !self.shut_down.load(Ordering::Relaxed)) else {
+            return;
+        };
+
+        self.check_server().await;
+
         // Get incoming messages
+        let mut buf = self.buffers.take_sized(MAX_DATAGRAM_SIZE);
+        while let Some(Ok((size, from))) = socket.recv_from(&mut buf).now_or_never() {
+            self.receive(from, &buf[..size]).await;
+        }
+        self.buffers.put(buf);
+
+        self.send_queued().await;
+    }
+
+    /// Wait for the next request for handlers, answering heartbeats and other built-in requests
+    /// along the way
+    ///
+    /// Returns `None` once the service is shut down.
+    pub async fn next_request(&self) -> Option<Request> {
+        let mut buf = self.buffers.take_sized(MAX_DATAGRAM_SIZE);
+
         loop {
-            let mut buf = [0u8; 65_535];
-            
-            match self.socket.recv(&mut buf).now_or_never().unwrap_or(Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to receive message"))) {
-                Ok(size) => {
-                    let content = &buf[..size];
-
-                    match serde_json::from_slice::<Request>(content) {
-                        Ok(msg) => {
-                            // Handle heartbeat immediately
-                            if msg.function == "heartbeat" {
-                                self.send_response(Response {
-                                    id: self.definition.id.clone(),
-                                    request: msg.id,
-                                    service: msg.service,
-                                    response: Some(alloc::vec![]),
-                                    event: None,
-                                    error: None,
-                                }).await.unwrap();
-                            } else {
-                                self.rx_queue.lock().unwrap().push_back(msg);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error parsing request: {}", e);
-                        }
-                    }
+            let socket = self.socket().filter(|_| !self.shut_down.load(Ordering::Relaxed))?;
+
+            if let Some(request) = self.rx_queue.pop() {
+                return Some(request);
+            }
+
+            self.send_queued().await;
+            self.check_server().await;
+
+            // Wake up for the next request timing out or server check even if nothing arrives
+            let check = self.resolver.as_ref().map(|r| r.next_check());
+            let deadline = self.deadlines.next_deadline().into_iter().chain(check).min();
+            let receive = async {
+                match deadline {
+                    Some(at) => with_timeout(at.saturating_duration_since(std::time::Instant::now()), socket.recv_from(&mut buf)).await,
+                    None => Some(socket.recv_from(&mut buf).await),
                 }
-                Err(_) => {
-                    break;
+            };
+            // Ends once shutdown closes the channel
+            let outgoing = async { self.response_rx.lock().await.next().await };
+            let received = {
+                let receive = core::pin::pin!(receive);
+                let outgoing = core::pin::pin!(outgoing);
+
+                match futures::future::select(receive, outgoing).await {
+                    Either::Left((received, _)) => received,
+                    Either::Right((Some(response), _)) => {
+                        self.send_queued_response(response).await;
+                        continue;
+                    }
+                    Either::Right((None, _)) => return None,
                 }
+            };
+
+            match received {
+                Some(Ok((size, from))) => self.receive(from, &buf[..size]).await,
+                Some(Err(e)) => error!("Error receiving request: {}", e),
+                None => {}
             }
         }
+    }
+
+    /// Check and handle a datagram from the socket
+    async fn receive(&self, from: SocketAddr, content: &[u8]) {
+        let server = self.server();
+        if !is_allowed_source(from, server, &self.allowed_sources, &self.stats) {
+            return;
+        }
+
+        if let (true, Some(resolver)) = (is_same_addr(from, server), &self.resolver) {
+            resolver.heard_from_server();
+        }
+
+        if let Some(msg) = parse_request(
+            content,
+            #[cfg(feature = "signing")]
+            self.signer.as_ref(),
+            &self.stats,
+        ) {
+            self.handle_request(msg).await;
+        }
+    }
+
+    /// Send timeout errors and queued messages, and drop idle sessions
+    async fn send_queued(&self) {
+        self.expire_requests().await;
+
+        if let Some(sessions) = &self.sessions {
+            sessions.expire();
+        }
 
         // Send queued messages
-        while !self.tx_queue.lock().unwrap().is_empty() {
-            let next_msg = self.tx_queue.lock().unwrap().pop_front().unwrap();
-            if let Err(e) = self.send_response(next_msg).await {
+        while let Some(next_msg) = self.tx_queue.pop() {
+            self.send_queued_response(next_msg).await;
+        }
+
+        // Send messages from the responses channel, unless next_request is already waiting on it
+        if let Some(mut responses) = self.response_rx.try_lock() {
+            while let Ok(next_msg) = responses.try_recv() {
+                self.send_queued_response(next_msg).await;
+            }
+        }
+    }
+
+    /// Send a response that was queued, unless its request already timed out
+    async fn send_queued_response(&self, response: Response) {
+        if response.event.is_none() && !self.deadlines.finish(&response.request) {
+            trace!("Dropping response to timed out request {}", response.request);
+            return;
+        }
+
+        if let Err(e) = self.send_response(response).await {
+            error!("Error sending response: {}", e);
+        }
+    }
+
+    /// Answer built-in requests and queue the rest for handlers
+    async fn handle_request(&self, msg: RequestRef<'_>) {
+        // Handle heartbeat immediately
+        if msg.function == "heartbeat" {
+            let sent = self.send_response(Response {
+                id: self.device_id(),
+                request: msg.id.into_owned(),
+                service: msg.service.into_owned(),
+                response: Some(alloc::vec![]),
+                event: None,
+                error: None,
+            }).await;
+            if let Err(e) = sent {
+                error!("Error answering heartbeat: {}", e);
+            }
+            return;
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(cache) = &self.response_cache {
+            match cache.check(&msg.id) {
+                Seen::New => {}
+                Seen::Pending => {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Seen::Answered(response) => {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = self.send_response(response).await {
+                        error!("Error sending response: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+
+        #[cfg(feature = "std")]
+        let id = msg.id.clone();
+        let mut msg = match msg.into_owned() {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Error parsing request: {}", e);
+                #[cfg(feature = "std")]
+                if let Some(cache) = &self.response_cache {
+                    cache.forget(&id);
+                }
+                return;
+            }
+        };
+
+        #[cfg(feature = "security")]
+        if let Some(security) = self.security.lock().unwrap().as_mut() {
+            if msg.function == security::REQUESTED_KEY_FUNCTION {
+                if let Err(e) = security.accept_key(&msg.params) {
+                    error!("Error reading key: {}", e);
+                }
+            } else {
+                security.decrypt_values(&mut msg.params);
+            }
+        }
+
+        if let Err(e) = self.check_request(&mut msg) {
+            let response = error_response(&self.device_id(), &msg, e);
+            if let Err(e) = self.send_response(response).await {
+                error!("Error sending response: {}", e);
+            }
+        } else {
+            if let Some(timeout) = self.method_timeout(&msg.function) {
+                self.deadlines.start(&msg, timeout);
+            }
+
+            touch_session(self.sessions.as_ref(), &msg);
+
+            self.rx_queue.push(msg);
+        }
+    }
+
+    /// Run the checks enabled on this service against an incoming request
+    fn check_request(&self, request: &mut Request) -> Result<(), String> {
+        check_access(self.access_control.as_ref(), request, &self.stats)?;
+
+        #[cfg(feature = "std")]
+        check_rate_limit(self.rate_limiter.as_ref(), request, &self.stats)?;
+
+        if self.validate_requests {
+            self.definition.lock().unwrap().validate_request(request)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ask the server for a new key, which arrives later as a `_requestedKey` request
+    #[cfg(feature = "security")]
+    pub async fn request_key(&self) -> Result<Delivery, std::io::Error> {
+        let call_id = format!("{}", self.next_msg_id.load(Ordering::Relaxed));
+        self.security.lock().unwrap().get_or_insert_with(Security::default).set_requested();
+        self.send_event(&call_id, security::REQUEST_KEY_EVENT, BTreeMap::new()).await
+    }
+
+    /// Clear the encryption settings on the server and forget the current key
+    #[cfg(feature = "security")]
+    pub async fn reset_key(&self) -> Result<Delivery, std::io::Error> {
+        let call_id = format!("{}", self.next_msg_id.load(Ordering::Relaxed));
+        let r = self.send_event(&call_id, security::RESET_EVENT, BTreeMap::new()).await;
+        if let Some(security) = self.security.lock().unwrap().as_mut() {
+            security.reset();
+        }
+        if let Some(sessions) = &self.sessions {
+            sessions.clear();
+        }
+        r
+    }
+
+    /// Send timeout errors for requests past their method's timeout
+    async fn expire_requests(&self) {
+        for (request, service) in self.deadlines.expire() {
+            self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+            let response = Response {
+                id: self.device_id(),
+                request,
+                service,
+                response: None,
+                event: None,
+                error: Some(TIMEOUT_ERROR.to_owned()),
+            };
+            if let Err(e) = self.send_response(response).await {
+                error!("Error sending response: {}", e);
+            }
+        }
+    }
+
+    /// Stop accepting requests and send what is left to send
+    ///
+    /// Requests not yet handled are answered with an error, then queued responses and events are
+    /// sent until the timeout passes. If `notify_server` is set, a [`DISCONNECT_EVENT`] is sent last
+    /// to tell the server the device is going away. The socket is released at the end, and closed
+    /// once calls still using it return.
+    pub async fn shutdown(&self, timeout: Duration, notify_server: bool) -> Result<(), String> {
+        let deadline = std::time::Instant::now() + timeout;
+        self.shut_down.store(true, Ordering::Relaxed);
+        self.response_tx.close_channel();
+        if let Some(sessions) = &self.sessions {
+            sessions.clear();
+        }
+
+        // Everything already queued goes out first, then the shutdown messages
+        let mut pending: VecDeque<Response> = core::iter::from_fn(|| self.tx_queue.pop()).collect();
+        if let Some(mut responses) = self.response_rx.try_lock() {
+            pending.extend(core::iter::from_fn(|| responses.try_recv().ok()));
+        }
+
+        let unhandled = core::iter::from_fn(|| self.rx_queue.pop());
+        let call_id = self.next_msg_id.load(Ordering::Relaxed);
+        let messages = shutdown_messages(&self.definition.lock().unwrap(), &self.name, call_id, unhandled, &self.deadlines, notify_server);
+        pending.extend(messages);
+
+        while let Some(next_msg) = pending.pop_front() {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                pending.push_front(next_msg);
+                break;
+            }
+
+            if next_msg.event.is_none() && !self.deadlines.finish(&next_msg.request) {
+                continue;
+            }
+
+            let sent = with_timeout(remaining, self.send_response(next_msg.clone())).await;
+            if let Some(Err(e)) = &sent {
                 error!("Error sending response: {}", e);
+                futures_timer::Delay::new(remaining.min(SHUTDOWN_RETRY_DELAY)).await;
+            }
+            if !matches!(sent, Some(Ok(_))) {
+                pending.push_front(next_msg);
             }
         }
+
+        self.socket.lock().unwrap().take();
+        drain_result(pending.len())
+    }
+
+    /// Run a handler for a request and send its result
+    ///
+    /// If the method has a timeout and the handler takes longer, the handler is cancelled and
+    /// the client is sent a timeout error instead.
+    pub async fn run_handler<F>(&self, request: Request, handler: F) -> Result<Option<Delivery>, std::io::Error>
+    where
+        F: core::future::Future<Output = Result<Vec<Value>, String>>,
+    {
+        let result = match self.method_timeout(&request.function) {
+            Some(timeout) => match with_timeout(timeout, handler).await {
+                Some(result) => result,
+                None => {
+                    // Skip if poll already sent the timeout error
+                    if !self.deadlines.finish(&request.id) {
+                        return Ok(None);
+                    }
+
+                    self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                    let response = error_response(&self.device_id(), &request, TIMEOUT_ERROR.to_owned());
+                    return self.send_response(response).await.map(Some);
+                }
+            },
+            None => handler.await,
+        };
+
+        self.respond(request, result).await.map(Some)
     }
 
     /// Create a response to an Request and enqueue it for sending
+    #[deprecated(note = "use `respond`, which reports how the response was sent")]
     pub async fn enqueue_response_to(
         &self,
         request: Request,
         params: Result<Vec<Value>, String>,
-    ) -> Result<usize, std::io::Error> {
-        let mut response = None;
-        let mut error = None;
+    ) -> Result<Delivery, std::io::Error> {
+        self.respond(request, params).await
+    }
 
-        match params {
-            Ok(p) => {
-                response = Some(p);
-            }
-            Err(e) => {
-                error = Some(e);
-            }
+    /// Send a response to a request the way `delivery` says
+    pub async fn respond(
+        &self,
+        request: Request,
+        params: Result<Vec<Value>, String>,
+    ) -> Result<Delivery, std::io::Error> {
+        if !self.deadlines.finish(&request.id) {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, TIMEOUT_ERROR));
         }
 
-        self.send_response(Response {
-            id: self.definition.id.clone(),
-            request: request.id.to_owned(),
-            service: request.service,
+        self.send_response(self.response_to(&request, params)).await
+    }
+
+    /// Take the oldest request waiting for a handler, without waiting for one to arrive
+    pub fn pop_request(&self) -> Option<Request> {
+        self.rx_queue.pop()
+    }
+
+    /// Queue a response or event for the next `poll` to send
+    pub fn queue_response(&self, response: Response) {
+        self.tx_queue.push(response);
+    }
+
+    /// Build the response to a request, for sending through [`IoTScapeServiceAsync::responses`]
+    pub fn response_to(&self, request: &Request, params: Result<Vec<Value>, String>) -> Response {
+        let (response, error) = match params {
+            Ok(p) => (Some(p), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        Response {
+            id: self.device_id(),
+            request: request.id.clone(),
+            service: request.service.clone(),
             response,
             event: None,
             error,
-        }).await
+        }
+    }
+
+    /// Requests for handlers as they arrive, ending once the service is shut down
+    pub fn requests(&self) -> impl futures::Stream<Item = Request> + Unpin + '_ {
+        alloc::boxed::Box::pin(futures::stream::unfold(self, |service| async move {
+            service.next_request().await.map(|request| (request, service))
+        }))
+    }
+
+    /// Sender for responses and events, which go out from `poll` or while waiting in `next_request`
+    pub fn responses(&self) -> UnboundedSender<Response> {
+        self.response_tx.clone()
     }
 
     /// Set an event message to be sent
-    pub async fn send_event(&self, call_id: &str, event_type: &str, args: BTreeMap<String, String>) -> Result<usize, std::io::Error> {
+    pub async fn send_event(&self, call_id: &str, event_type: &str, args: BTreeMap<String, String>) -> Result<Delivery, std::io::Error> {
         self.send_response(Response {
-            id: self.definition.id.clone(),
+            id: self.device_id(),
             request: call_id.to_owned(),
             service: self.name.to_owned(),
             response: None,
             event: Some(EventResponse {
-                r#type: Some(event_type.to_owned()),
-                args: Some(args),
+                r#type: event_type.to_owned(),
+                args,
             }),
             error: None,
         }).await
     }
 
+    fn serialize_response(&self, response: &Response, buf: &mut Vec<u8>) {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer {
+            signer.sign_response(response, buf);
+            return;
+        }
+
+        write_json(buf, response);
+    }
+
     /// Sends an Response to ther server
-    async fn send_response(&self, response: Response) -> Result<usize, std::io::Error>{
-        let as_string = serde_json::to_string(&response).unwrap();
-        trace!("Sending response {:?}", as_string);
-        let r = self.socket
-            .send_to(as_string.as_bytes(), self.server).await;
-        self.next_msg_id.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    async fn send_response(&self, #[allow(unused_mut)] mut response: Response) -> Result<Delivery, std::io::Error>{
+        #[cfg(feature = "std")]
+        if let (Some(cache), None) = (&self.response_cache, &response.event) {
+            cache.store(&response);
+        }
+
+        #[cfg(feature = "security")]
+        if let Some(security) = self.security.lock().unwrap().as_ref() {
+            encrypt_response(security, &mut response);
+        }
+
+        let mut buf = self.buffers.take();
+        self.serialize_response(&response, &mut buf);
+        trace!("Sending response {:?}", String::from_utf8_lossy(&buf));
+        let r = self.deliver(&buf, Outgoing::Response).await;
+        self.buffers.put(buf);
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed);
         r
     }
 
-    /// Create a response to an Request and enqueue it for sending
+    /// Send a serialized message to the server the way `delivery` says
+    async fn deliver(&self, buf: &[u8], kind: Outgoing) -> Result<Delivery, std::io::Error> {
+        match self.delivery {
+            DeliveryPolicy::UdpOnly => {
+                let delivery = self.open_socket()?.delivery();
+                self.send_to_server(buf).await.map(|_| delivery)
+            }
+            DeliveryPolicy::HttpOnly => self.send_http(buf, kind).await,
+            DeliveryPolicy::UdpFirst => {
+                // Large messages may not make it through UDP, other sockets take any size
+                let delivery = self.open_socket()?.delivery();
+                if delivery == Delivery::Udp && buf.len() > self.max_udp_size && self.has_http(kind) {
+                    return self.send_http(buf, kind).await;
+                }
+
+                match self.send_to_server(buf).await {
+                    Ok(_) => Ok(delivery),
+                    Err(e) if self.has_http(kind) => {
+                        log::warn!("Sending over UDP failed, using HTTP: {}", e);
+                        self.send_http(buf, kind).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Whether messages of a kind can be sent over HTTP
+    #[cfg_attr(not(any(feature = "http_announce", feature = "http_response")), allow(unused_variables))]
+    fn has_http(&self, kind: Outgoing) -> bool {
+        #[cfg(any(feature = "http_announce", feature = "http_response"))]
+        return kind.http_enabled() && self.http.is_some();
+
+        #[cfg(not(any(feature = "http_announce", feature = "http_response")))]
+        false
+    }
+
+    /// Send a serialized message to its URL in `http`
+    #[cfg(any(feature = "http_announce", feature = "http_response"))]
+    async fn send_http(&self, buf: &[u8], kind: Outgoing) -> Result<Delivery, std::io::Error> {
+        if !kind.http_enabled() {
+            return Err(std::io::Error::other(HTTP_DISABLED_ERROR));
+        }
+
+        let http = self.http.as_ref().ok_or_else(|| std::io::Error::other(NO_HTTP_ERROR))?;
+        let response = match kind {
+            Outgoing::Announce => http.announce(buf.to_vec()).await?,
+            Outgoing::Response => http.respond(buf.to_vec()).await?,
+        };
+        if !response.is_success() {
+            return Err(std::io::Error::other(format!("HTTP server answered with status {}", response.status)));
+        }
+        Ok(Delivery::Http)
+    }
+
+    #[cfg(not(any(feature = "http_announce", feature = "http_response")))]
+    async fn send_http(&self, _buf: &[u8], _kind: Outgoing) -> Result<Delivery, std::io::Error> {
+        Err(std::io::Error::other(HTTP_DISABLED_ERROR))
+    }
+
+    /// Create a response to an Request and send it to the response URL of `http`
     #[cfg(feature = "http_response")]
     pub async fn enqueue_response_to_http(
         &self, 
-        endpoint: &str,
         request: Request,
         params: Result<Vec<Value>, String>,
-    ) -> Result<reqwest::Response, reqwest::Error> {
+    ) -> Result<HttpResponse, std::io::Error> {
+        // Stop the clock, the server drops a response arriving after the timeout error
+        self.deadlines.finish(&request.id);
+
         let mut response = None;
         let mut error = None;
 
@@ -582,8 +2181,8 @@ impl<SocketType: SocketTraitAsync> IoTScapeServiceAsync<SocketType> {
             }
         }
 
-        self.send_response_http(endpoint, Response {
-            id: self.definition.id.clone(),
+        self.send_response_http(Response {
+            id: self.device_id(),
             request: request.id.to_owned(),
             service: request.service,
             response,
@@ -593,10 +2192,20 @@ impl<SocketType: SocketTraitAsync> IoTScapeServiceAsync<SocketType> {
     }
     
     #[cfg(feature = "http_response")]
-    async fn send_response_http(&self, endpoint: &str, response: Response) -> Result<reqwest::Response, reqwest::Error> {
-        self.client.post(endpoint)
-            .body(serde_json::to_string(&response).unwrap())
-            .header("Content-Type", "application/json")
-            .send().await
+    async fn send_response_http(&self, #[allow(unused_mut)] mut response: Response) -> Result<HttpResponse, std::io::Error> {
+        let http = self.http.as_ref().ok_or_else(|| std::io::Error::other(NO_HTTP_ERROR))?;
+
+        if let (Some(cache), None) = (&self.response_cache, &response.event) {
+            cache.store(&response);
+        }
+
+        #[cfg(feature = "security")]
+        if let Some(security) = self.security.lock().unwrap().as_ref() {
+            encrypt_response(security, &mut response);
+        }
+
+        let mut buf = Vec::new();
+        self.serialize_response(&response, &mut buf);
+        http.respond(buf).await
     }
 }
\ No newline at end of file
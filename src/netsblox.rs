@@ -0,0 +1,147 @@
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec,
+    vec::Vec,
+};
+
+use serde_json::Value;
+
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+/// Conversion of Rust data into the nested-list values NetsBlox blocks expect
+///
+/// Sequences become lists, tuples become lists of their elements, and maps become tables,
+/// i.e. lists of `[key, value]` rows. Structs can implement it with `#[derive(IntoNetsBlox)]`
+/// when the `derive` feature is enabled, which turns named fields into `[name, value]` rows.
+pub trait IntoNetsBlox {
+    fn into_netsblox(self) -> Value;
+
+    /// Values to send as the response to a method call
+    fn into_response(self) -> Vec<Value>
+    where
+        Self: Sized,
+    {
+        vec![self.into_netsblox()]
+    }
+}
+
+impl IntoNetsBlox for Value {
+    fn into_netsblox(self) -> Value {
+        self
+    }
+}
+
+impl IntoNetsBlox for () {
+    fn into_netsblox(self) -> Value {
+        Value::Array(Vec::new())
+    }
+
+    fn into_response(self) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_into_netsblox_from {
+    ($($t:ty),*) => {
+        $(
+            impl IntoNetsBlox for $t {
+                fn into_netsblox(self) -> Value {
+                    Value::from(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_into_netsblox_from!(bool, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64, String);
+
+impl IntoNetsBlox for &str {
+    fn into_netsblox(self) -> Value {
+        Value::String(self.to_owned())
+    }
+}
+
+impl IntoNetsBlox for char {
+    fn into_netsblox(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+/// `None` becomes an empty string, which is how NetsBlox shows an empty slot
+impl<T: IntoNetsBlox> IntoNetsBlox for Option<T> {
+    fn into_netsblox(self) -> Value {
+        match self {
+            Some(value) => value.into_netsblox(),
+            None => Value::String(String::new()),
+        }
+    }
+}
+
+impl<T: IntoNetsBlox> IntoNetsBlox for Box<T> {
+    fn into_netsblox(self) -> Value {
+        (*self).into_netsblox()
+    }
+}
+
+impl<T: IntoNetsBlox> IntoNetsBlox for Vec<T> {
+    fn into_netsblox(self) -> Value {
+        Value::Array(self.into_iter().map(IntoNetsBlox::into_netsblox).collect())
+    }
+}
+
+impl<T: IntoNetsBlox> IntoNetsBlox for VecDeque<T> {
+    fn into_netsblox(self) -> Value {
+        Value::Array(self.into_iter().map(IntoNetsBlox::into_netsblox).collect())
+    }
+}
+
+impl<T: IntoNetsBlox, const N: usize> IntoNetsBlox for [T; N] {
+    fn into_netsblox(self) -> Value {
+        Value::Array(self.into_iter().map(IntoNetsBlox::into_netsblox).collect())
+    }
+}
+
+impl<K: IntoNetsBlox, V: IntoNetsBlox> IntoNetsBlox for BTreeMap<K, V> {
+    fn into_netsblox(self) -> Value {
+        Value::Array(self.into_iter().map(IntoNetsBlox::into_netsblox).collect())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: IntoNetsBlox, V: IntoNetsBlox, S> IntoNetsBlox for HashMap<K, V, S> {
+    fn into_netsblox(self) -> Value {
+        Value::Array(self.into_iter().map(IntoNetsBlox::into_netsblox).collect())
+    }
+}
+
+#[cfg(feature = "costume")]
+impl IntoNetsBlox for crate::Costume {
+    fn into_netsblox(self) -> Value {
+        self.into()
+    }
+}
+
+macro_rules! impl_into_netsblox_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: IntoNetsBlox),+> IntoNetsBlox for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_netsblox(self) -> Value {
+                let ($($name,)+) = self;
+                Value::Array(vec![$($name.into_netsblox()),+])
+            }
+        }
+    };
+}
+
+impl_into_netsblox_tuple!(A);
+impl_into_netsblox_tuple!(A, B);
+impl_into_netsblox_tuple!(A, B, C);
+impl_into_netsblox_tuple!(A, B, C, D);
+impl_into_netsblox_tuple!(A, B, C, D, E);
+impl_into_netsblox_tuple!(A, B, C, D, E, F);
+impl_into_netsblox_tuple!(A, B, C, D, E, F, G);
+impl_into_netsblox_tuple!(A, B, C, D, E, F, G, H);
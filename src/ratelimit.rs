@@ -0,0 +1,127 @@
+use alloc::{borrow::ToOwned, collections::BTreeMap, string::String};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use crate::Request;
+
+/// Buckets above this count are pruned of ones that have refilled completely
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Token bucket settings: up to `burst` calls at once, refilled at `per_second` calls per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst: burst as f64,
+            per_second,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated = now;
+    }
+}
+
+/// Limits how often each NetsBlox client can call the service
+///
+/// Every client gets its own bucket for calls to methods without their own limit, and one
+/// bucket per method for methods with a limit set by [`RateLimiter::with_method_limit`].
+///
+/// Requests without a `clientId` all share one set of buckets. They are relayed by the server,
+/// so their source address can't tell their senders apart either.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Limit for calls to methods without their own limit, unlimited if not set
+    pub default: Option<RateLimit>,
+    pub methods: BTreeMap<String, RateLimit>,
+    buckets: Mutex<HashMap<(String, Option<String>), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: RateLimit) -> Self {
+        Self {
+            default: Some(default),
+            methods: BTreeMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limit only the methods given a limit with [`RateLimiter::with_method_limit`]
+    pub fn per_method() -> Self {
+        Self {
+            default: None,
+            methods: BTreeMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set a separate limit for calls to a method
+    pub fn with_method_limit(mut self, method: &str, limit: RateLimit) -> Self {
+        self.methods.insert(method.to_owned(), limit);
+        self
+    }
+
+    /// Take a token for a request, returning false if the client is over its limit
+    pub fn check(&self, request: &Request) -> bool {
+        let now = Instant::now();
+        let (limit, method) = match self.methods.get(&request.function) {
+            Some(limit) => (limit, Some(request.function.clone())),
+            None => match &self.default {
+                Some(limit) => (limit, None),
+                None => return true,
+            },
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            self.prune(&mut buckets, now);
+        }
+
+        let bucket = buckets
+            .entry((request.client_id.clone().unwrap_or_default(), method))
+            .or_insert(TokenBucket {
+                tokens: limit.burst,
+                updated: now,
+            });
+        bucket.refill(limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop buckets that are full, which behave the same as new ones
+    fn prune(&self, buckets: &mut HashMap<(String, Option<String>), TokenBucket>, now: Instant) {
+        buckets.retain(|(_, method), bucket| {
+            let limit = match method {
+                Some(method) => self.methods.get(method),
+                None => self.default.as_ref(),
+            };
+
+            match limit {
+                Some(limit) => {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst
+                }
+                None => false,
+            }
+        });
+    }
+}
@@ -0,0 +1,223 @@
+//! Keeping a service pointed at a live server by looking its name up again and failing over
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+use core::time::Duration;
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+
+/// Default time between lookups of the current server's name
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Default time without hearing from the server before counting a failure, once there are
+/// fallback servers to move to
+///
+/// Sends over UDP rarely fail even when nothing is listening, so silence is what shows a server
+/// is down. The server sends heartbeats more often than this.
+pub const DEFAULT_SERVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Time between lookups while the current server is failing
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Look up a `host:port` server, preferring IPv4 as services bind an IPv4 socket
+pub fn resolve(server: &str) -> Result<SocketAddr, String> {
+    let addrs: Vec<SocketAddr> = server
+        .to_socket_addrs()
+        .map_err(|e| format!("Could not resolve {}: {}", server, e))?
+        .collect();
+
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| format!("No addresses found for {}", server))
+}
+
+/// [`resolve`] on its own thread, so lookups don't block the runtime
+#[cfg(feature = "async")]
+pub(crate) async fn resolve_async(server: String) -> Result<SocketAddr, String> {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(resolve(&server));
+    });
+    rx.await.unwrap_or_else(|_| Err("Lookup thread stopped".to_owned()))
+}
+
+#[derive(Debug)]
+struct State {
+    current: usize,
+    failures: u32,
+    last_heard: Instant,
+    last_lookup: Instant,
+    next_lookup: Instant,
+}
+
+/// Servers a service may talk to, in order of preference, and when to look them up again
+///
+/// The current server's name is looked up every `refresh_interval`, and again soon after sends
+/// to it fail or, if `server_timeout` is set, it goes quiet. After `max_failures` failures in a
+/// row the service moves on to the next server, going back to the first after the last. The
+/// service announces itself again whenever the address it sends to changes.
+#[derive(Debug)]
+pub struct ServerResolver {
+    servers: Vec<String>,
+    /// Time between lookups of the current server's name
+    pub refresh_interval: Duration,
+    /// Count a failure whenever nothing arrives from the server for this long, if set
+    ///
+    /// The server sends heartbeats, so this should be a few times their interval. Adding a
+    /// fallback sets this to [`DEFAULT_SERVER_TIMEOUT`] if it is not set yet.
+    pub server_timeout: Option<Duration>,
+    /// Failures in a row before moving to the next server
+    pub max_failures: u32,
+    state: Mutex<State>,
+    /// Lookup running on its own thread for `check`
+    pending: Mutex<Option<mpsc::Receiver<Result<SocketAddr, String>>>>,
+}
+
+impl ServerResolver {
+    /// Resolve `server`, as `host:port`, where the host may be a DNS name
+    pub fn new(server: &str) -> Self {
+        let now = Instant::now();
+        Self {
+            servers: alloc::vec![server.to_owned()],
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            server_timeout: None,
+            max_failures: 3,
+            state: Mutex::new(State {
+                current: 0,
+                failures: 0,
+                last_heard: now,
+                last_lookup: now,
+                next_lookup: now + DEFAULT_REFRESH_INTERVAL,
+            }),
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// Add a server to move to if the ones before it fail
+    pub fn with_fallback(mut self, server: &str) -> Self {
+        self.servers.push(server.to_owned());
+        self.server_timeout.get_or_insert(DEFAULT_SERVER_TIMEOUT);
+        self
+    }
+
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self.state.get_mut().unwrap().next_lookup = Instant::now() + interval;
+        self
+    }
+
+    pub fn with_server_timeout(mut self, timeout: Duration) -> Self {
+        self.server_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// All servers, in order of preference
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// The server currently in use
+    pub fn current(&self) -> &str {
+        &self.servers[self.state.lock().unwrap().current]
+    }
+
+    /// Note that something arrived from the current server
+    pub(crate) fn heard_from_server(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.last_heard = Instant::now();
+        state.failures = 0;
+    }
+
+    /// Note that a send to the current server failed, looking it up again soon
+    pub(crate) fn report_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        state.next_lookup = state.next_lookup.min(state.last_lookup + RETRY_DELAY);
+    }
+
+    /// When `next_lookup` should be called again
+    #[cfg(feature = "async")]
+    pub(crate) fn next_check(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        match self.server_timeout {
+            Some(timeout) => state.next_lookup.min(state.last_heard + timeout),
+            None => state.next_lookup,
+        }
+    }
+
+    /// The server to look up now, if a lookup is due, moving to the next server if the current
+    /// one failed too often
+    pub(crate) fn next_lookup(&self) -> Option<String> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        // Silence counts as a failure once per timeout, so it is checked right away
+        let silent = self
+            .server_timeout
+            .is_some_and(|timeout| now.saturating_duration_since(state.last_heard) >= timeout);
+        if silent {
+            state.failures += 1;
+            state.last_heard = now;
+        } else if now < state.next_lookup {
+            return None;
+        }
+
+        if state.failures >= self.max_failures && self.servers.len() > 1 {
+            state.current = (state.current + 1) % self.servers.len();
+            state.failures = 0;
+            state.last_heard = now;
+            log::warn!("Moving to server {}", self.servers[state.current]);
+        }
+
+        state.last_lookup = now;
+        state.next_lookup = now + if state.failures > 0 { RETRY_DELAY } else { self.refresh_interval };
+        Some(self.servers[state.current].clone())
+    }
+
+    /// The new address of the server once a lookup started by an earlier call finishes, without
+    /// blocking on the lookup
+    pub(crate) fn check(&self) -> Option<SocketAddr> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(lookup) = pending.as_ref() {
+            match lookup.try_recv() {
+                Ok(result) => {
+                    *pending = None;
+                    return self.looked_up(result);
+                }
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => *pending = None,
+            }
+        }
+
+        if let Some(server) = self.next_lookup() {
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = tx.send(resolve(&server));
+            });
+            *pending = Some(rx);
+        }
+        None
+    }
+
+    /// Record the result of a lookup from `next_lookup`, returning the address if it succeeded
+    pub(crate) fn looked_up(&self, result: Result<SocketAddr, String>) -> Option<SocketAddr> {
+        match result {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                log::error!("{}", e);
+                self.state.lock().unwrap().failures += 1;
+                None
+            }
+        }
+    }
+}
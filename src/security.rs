@@ -0,0 +1,181 @@
+use alloc::{
+    borrow::ToOwned,
+    string::String,
+    vec::Vec,
+};
+
+use serde_json::Value;
+
+/// Event sent to ask the server for a new device key
+pub const REQUEST_KEY_EVENT: &str = "_requestKey";
+
+/// Event sent to clear the encryption settings on the server
+pub const RESET_EVENT: &str = "_reset";
+
+/// Request sent by the server with the key it generated for the device
+pub const REQUESTED_KEY_FUNCTION: &str = "_requestedKey";
+
+/// First and last characters shifted by the caesar cipher, other characters are sent as-is
+const PRINTABLE_START: u32 = 0x20;
+const PRINTABLE_END: u32 = 0x7E;
+const PRINTABLE_LEN: u32 = PRINTABLE_END - PRINTABLE_START + 1;
+
+/// Cipher applied to the text in messages once a key is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    /// Messages are sent unchanged
+    Plain,
+    /// Each printable ASCII character is shifted by the key digit at its position, wrapping
+    /// around within the printable range
+    ///
+    /// This follows the description of the NetsBlox IoTScape cipher, but has not been checked
+    /// against the server's implementation. Test the key exchange with your server before relying
+    /// on it.
+    #[default]
+    Caesar,
+}
+
+/// Where the device is in the key exchange with the server
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum KeyState {
+    #[default]
+    None,
+    /// `_requestKey` was sent, waiting for `_requestedKey`
+    Requested,
+    Active(Vec<u8>),
+}
+
+/// Device key storage and message encryption
+#[derive(Debug, Clone, Default)]
+pub struct Security {
+    pub cipher: Cipher,
+    state: KeyState,
+}
+
+impl Security {
+    pub fn new(cipher: Cipher) -> Self {
+        Self {
+            cipher,
+            state: KeyState::None,
+        }
+    }
+
+    pub fn state(&self) -> &KeyState {
+        &self.state
+    }
+
+    /// Key in use, if any
+    pub fn key(&self) -> Option<&[u8]> {
+        match &self.state {
+            KeyState::Active(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Mark a key as requested from the server
+    pub fn set_requested(&mut self) {
+        self.state = KeyState::Requested;
+    }
+
+    /// Use a key, e.g. one restored from storage, an empty key disables encryption
+    pub fn set_key(&mut self, key: Vec<u8>) {
+        self.state = if key.is_empty() { KeyState::None } else { KeyState::Active(key) };
+    }
+
+    /// Forget the current key
+    pub fn reset(&mut self) {
+        self.state = KeyState::None;
+    }
+
+    /// Store the key from the params of a `_requestedKey` request
+    ///
+    /// The key may be sent as a list of numbers or as text of digits, optionally separated
+    /// by commas or spaces.
+    pub fn accept_key(&mut self, params: &[Value]) -> Result<(), String> {
+        let key = parse_key(params)?;
+        self.set_key(key);
+        Ok(())
+    }
+
+    /// Encrypt text to be sent to the server
+    pub fn encrypt(&self, text: &str) -> String {
+        self.apply(text, true)
+    }
+
+    /// Decrypt text received from the server
+    pub fn decrypt(&self, text: &str) -> String {
+        self.apply(text, false)
+    }
+
+    /// Encrypt all text in values to be sent to the server
+    pub fn encrypt_values(&self, values: &mut [Value]) {
+        if self.key().is_some() {
+            values.iter_mut().for_each(|v| self.apply_value(v, true));
+        }
+    }
+
+    /// Decrypt all text in values received from the server
+    pub fn decrypt_values(&self, values: &mut [Value]) {
+        if self.key().is_some() {
+            values.iter_mut().for_each(|v| self.apply_value(v, false));
+        }
+    }
+
+    fn apply_value(&self, value: &mut Value, encrypt: bool) {
+        match value {
+            Value::String(s) => *s = self.apply(s, encrypt),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.apply_value(v, encrypt)),
+            _ => {}
+        }
+    }
+
+    fn apply(&self, text: &str, encrypt: bool) -> String {
+        let key = match (self.cipher, self.key()) {
+            (Cipher::Caesar, Some(key)) => key,
+            _ => return text.to_owned(),
+        };
+
+        text.chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let code = c as u32;
+                if !(PRINTABLE_START..=PRINTABLE_END).contains(&code) {
+                    return c;
+                }
+
+                let shift = key[i % key.len()] as u32 % PRINTABLE_LEN;
+                let offset = code - PRINTABLE_START;
+                let shifted = if encrypt {
+                    (offset + shift) % PRINTABLE_LEN
+                } else {
+                    (offset + PRINTABLE_LEN - shift) % PRINTABLE_LEN
+                };
+                char::from_u32(shifted + PRINTABLE_START).unwrap_or(c)
+            })
+            .collect()
+    }
+}
+
+fn parse_key(params: &[Value]) -> Result<Vec<u8>, String> {
+    let mut key = Vec::new();
+
+    for param in params {
+        match param {
+            Value::Number(n) => key.push(n.as_u64().filter(|n| *n <= u8::MAX as u64).ok_or("Key values must be 0-255")? as u8),
+            Value::String(s) if s.contains([',', ' ']) => {
+                for part in s.split([',', ' ']).filter(|p| !p.is_empty()) {
+                    key.push(part.trim().parse::<u8>().map_err(|_| "Invalid key value: ".to_owned() + part)?);
+                }
+            }
+            Value::String(s) => {
+                for c in s.chars() {
+                    key.push(c.to_digit(10).ok_or("Invalid key digit: ".to_owned() + s)? as u8);
+                }
+            }
+            Value::Array(items) => key.extend(parse_key(items)?),
+            _ => return Err("Unsupported key format".to_owned()),
+        }
+    }
+
+    Ok(key)
+}
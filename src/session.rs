@@ -0,0 +1,112 @@
+use alloc::{borrow::ToOwned, boxed::Box, string::String};
+use core::{
+    any::{Any, TypeId},
+    time::Duration,
+};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+#[derive(Debug)]
+struct Session {
+    last_seen: Instant,
+    /// One value of each type handlers keep for the client
+    data: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            last_seen: Instant::now(),
+            data: HashMap::new(),
+        }
+    }
+}
+
+/// State kept for each NetsBlox client between requests
+///
+/// A client's session holds one value of each type handlers store, so handlers using different
+/// types don't see each other's state. Sessions are dropped once their client has not sent a
+/// request for longer than the time to live, or when the device resets its key.
+///
+/// The server does not tell devices when it restarts or forgets a client, so sessions are not
+/// cleared on a server reset. Keep the time to live short enough for stale sessions to expire.
+#[derive(Debug)]
+pub struct SessionStore {
+    /// How long a session is kept after its client's last request
+    pub ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run a function on a client's session value of a type, starting with the default value if
+    /// the client has none of that type
+    pub fn with<T: Any + Send + Default, R>(&self, client_id: &str, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(client_id.to_owned()).or_insert_with(Session::new);
+        let data = session
+            .data
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()));
+
+        f(data.downcast_mut::<T>().unwrap())
+    }
+
+    /// Copy of a client's session value of a type, if it has one
+    pub fn get<T: Any + Send + Clone>(&self, client_id: &str) -> Option<T> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .and_then(|session| session.data.get(&TypeId::of::<T>()))
+            .and_then(|data| data.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// Set a client's session value of a type
+    pub fn insert<T: Any + Send>(&self, client_id: &str, data: T) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(client_id.to_owned()).or_insert_with(Session::new);
+        session.last_seen = Instant::now();
+        session.data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+
+    /// End a client's session, dropping its values of every type, returning whether it had one
+    pub fn remove(&self, client_id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(client_id).is_some()
+    }
+
+    /// End all sessions
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keep a client's session alive after a request from it
+    pub(crate) fn touch(&self, client_id: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(client_id) {
+            session.last_seen = Instant::now();
+        }
+    }
+
+    /// Drop sessions of clients that stopped sending requests
+    pub(crate) fn expire(&self) {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| now.saturating_duration_since(session.last_seen) <= self.ttl);
+    }
+}
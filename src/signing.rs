@@ -0,0 +1,253 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+#[cfg(feature = "std")]
+use core::{sync::atomic::{AtomicU64, Ordering}, time::Duration};
+#[cfg(feature = "std")]
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+#[cfg(feature = "std")]
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{write_json, Request, Response};
+
+/// Name of the field holding the signature of a message
+pub const SIGNATURE_FIELD: &str = "signature";
+
+/// Default time a signed message is accepted for after it was sent
+#[cfg(feature = "std")]
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Reason a request was rejected by a [`Signer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Invalid,
+    /// Not a JSON object, or not a request once the signature checked out
+    Malformed,
+    /// Signed correctly, but sent outside the freshness window, without a timestamp or nonce, or
+    /// seen before
+    Replayed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => f.write_str("Missing signature"),
+            SignatureError::Invalid => f.write_str("Invalid signature"),
+            SignatureError::Malformed => f.write_str("Malformed request"),
+            SignatureError::Replayed => f.write_str("Stale or replayed request"),
+        }
+    }
+}
+
+/// Fields of a signed message that show it is fresh
+#[cfg(feature = "std")]
+#[derive(Deserialize)]
+struct Freshness {
+    /// Unix time in milliseconds the message was signed at
+    timestamp: Option<u64>,
+    nonce: Option<String>,
+}
+
+/// HMAC-SHA256 signing of device traffic with a secret shared with the server
+///
+/// The signature is computed over the compact JSON serialization of the message, exactly as it
+/// is sent, then added hex encoded as a last `signature` field. Verifying strips that field from
+/// the received bytes instead of serializing the message again, so key order and number
+/// formatting are whatever the sender used.
+///
+/// With the `std` feature, signed messages also carry a `timestamp`, in Unix milliseconds, and a
+/// `nonce`. Requests are rejected if their timestamp is more than `max_age` away from now or their
+/// nonce was already seen within that window, so captured requests can't be sent again.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+    /// How far a request's timestamp may be from now
+    #[cfg(feature = "std")]
+    pub max_age: Duration,
+    /// Nonces of verified requests, with their timestamps, shared between clones
+    #[cfg(feature = "std")]
+    seen: Arc<Mutex<BTreeMap<String, u64>>>,
+    #[cfg(feature = "std")]
+    next_nonce: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer").finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+            #[cfg(feature = "std")]
+            max_age: DEFAULT_MAX_AGE,
+            #[cfg(feature = "std")]
+            seen: Arc::default(),
+            #[cfg(feature = "std")]
+            next_nonce: Arc::default(),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    fn signature(&self, message: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(message);
+        mac
+    }
+
+    /// Serialize a response with its signature into `buf`, replacing what it held
+    pub fn sign_response(&self, response: &Response, buf: &mut Vec<u8>) {
+        self.sign(response, buf);
+    }
+
+    /// Serialize a request with its signature, as the server would send it
+    pub fn sign_request(&self, request: &Request) -> String {
+        let mut buf = Vec::new();
+        self.sign(request, &mut buf);
+        String::from_utf8(buf).expect("JSON is UTF-8")
+    }
+
+    fn sign<T: Serialize>(&self, message: &T, buf: &mut Vec<u8>) {
+        write_json(buf, message);
+
+        #[cfg(feature = "std")]
+        {
+            let timestamp = now_millis();
+            let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed);
+            push_field(buf, "timestamp", timestamp);
+            push_field(buf, "nonce", format_args!("\"{:x}-{:x}\"", timestamp, nonce));
+        }
+
+        // Add the signature as the last field
+        let signature = to_hex(&self.signature(buf).finalize().into_bytes());
+        push_field(buf, SIGNATURE_FIELD, format_args!("\"{}\"", signature));
+    }
+
+    /// Check the signature of a serialized request and parse it
+    pub fn verify_request(&self, content: &[u8]) -> Result<Request, SignatureError> {
+        self.verify(content)?;
+        serde_json::from_slice(content).map_err(|_| SignatureError::Malformed)
+    }
+
+    /// Check the signature of a serialized message, and with the `std` feature that it is fresh
+    pub fn verify(&self, content: &[u8]) -> Result<(), SignatureError> {
+        let (message, signature) = split_signature(content)?;
+        self.signature(&message)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Invalid)?;
+
+        #[cfg(feature = "std")]
+        self.check_fresh(&message)?;
+
+        Ok(())
+    }
+
+    /// Check the timestamp and nonce of a message with a valid signature, remembering the nonce
+    #[cfg(feature = "std")]
+    fn check_fresh(&self, message: &[u8]) -> Result<(), SignatureError> {
+        let freshness: Freshness = serde_json::from_slice(message).map_err(|_| SignatureError::Malformed)?;
+        let (timestamp, nonce) = match freshness {
+            Freshness {
+                timestamp: Some(timestamp),
+                nonce: Some(nonce),
+            } => (timestamp, nonce),
+            _ => return Err(SignatureError::Replayed),
+        };
+
+        let now = now_millis();
+        let max_age = self.max_age.as_millis() as u64;
+        if now.abs_diff(timestamp) > max_age {
+            return Err(SignatureError::Replayed);
+        }
+
+        // Nonces older than the window can be forgotten, as their messages are rejected anyway
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, &mut seen_at| now.abs_diff(seen_at) <= max_age);
+        if seen.insert(nonce, timestamp).is_some() {
+            return Err(SignatureError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+/// Add a field with an already serialized value to the end of a serialized object
+fn push_field(buf: &mut Vec<u8>, name: &str, value: impl fmt::Display) {
+    buf.pop();
+    if buf.last() != Some(&b'{') {
+        buf.push(b',');
+    }
+    buf.extend_from_slice(alloc::format!("\"{}\":{}}}", name, value).as_bytes());
+}
+
+/// Split a serialized message into the bytes that were signed and its signature
+fn split_signature(content: &[u8]) -> Result<(Vec<u8>, Vec<u8>), SignatureError> {
+    let content = content.trim_ascii();
+    if !content.starts_with(b"{") || !content.ends_with(b"}") {
+        return Err(SignatureError::Malformed);
+    }
+
+    let field = alloc::format!("\"{}\":\"", SIGNATURE_FIELD);
+    let start = match content.windows(field.len()).rposition(|w| w == field.as_bytes()) {
+        Some(start) => start,
+        None => return Err(SignatureError::Missing),
+    };
+
+    // The signature must be the last field of the message itself
+    let hex = content[start + field.len()..]
+        .strip_suffix(b"\"}")
+        .and_then(|hex| core::str::from_utf8(hex).ok())
+        .and_then(from_hex)
+        .ok_or(SignatureError::Invalid)?;
+
+    let mut message = content[..start].to_vec();
+    if message.ends_with(b",") {
+        message.pop();
+    } else if message != b"{" {
+        return Err(SignatureError::Invalid);
+    }
+    message.push(b'}');
+    Ok((message, hex))
+}
+
+#[cfg(feature = "std")]
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push(DIGITS[(b >> 4) as usize] as char);
+        hex.push(DIGITS[(b & 0xF) as usize] as char);
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
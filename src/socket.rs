@@ -4,12 +4,15 @@ extern crate std;
 
 use alloc::{
     collections::VecDeque,
-    string::{String, ToString},
+    string::String,
     vec::Vec, 
-    format,
 };
+#[cfg(feature = "std")]
+use alloc::{format, string::ToString};
 use core::time::Duration;
 
+use crate::Delivery;
+
 #[cfg(feature = "std")]
 use std::net::SocketAddr;
 
@@ -22,35 +25,49 @@ use std::net::UdpSocket as StdUdpSocket;
 #[cfg(feature = "tokio")]
 use tokio::net::UdpSocket as TokioUdpSocket;
 
+#[cfg(feature = "smol")]
+use async_io::Async;
+
 
 /// Trait to allow various socket types to be used with IoTScapeService
 pub trait SocketTrait : Sized {
     fn bind(addrs: &[SocketAddr]) -> Result<Self, String>;
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, String>;
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, String>;
+    /// Receive a datagram, returning its size and the address it came from
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), String>;
     fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), String>;
     fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), String>;
+    /// How messages sent through this socket reach the server
+    fn delivery(&self) -> Delivery {
+        Delivery::Udp
+    }
 }
 
-#[cfg(feature = "tokio")]
+/// Trait to allow various socket types to be used with IoTScapeServiceAsync, on any async runtime
+#[cfg(feature = "async")]
 pub trait SocketTraitAsync : Sized {
     fn bind(addr: &SocketAddr) -> impl std::future::Future<Output = Result<Self, std::io::Error>> + Send;
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send;
-    fn recv(&self, buf: &mut [u8]) -> impl std::future::Future<Output = Result<usize, std::io::Error>> + Send;
+    /// Receive a datagram, returning its size and the address it came from
+    fn recv_from(&self, buf: &mut [u8]) -> impl std::future::Future<Output = Result<(usize, SocketAddr), std::io::Error>> + Send;
+    /// How messages sent through this socket reach the server
+    fn delivery(&self) -> Delivery {
+        Delivery::Udp
+    }
 }
 
 #[cfg(feature = "std")]
 impl SocketTrait for StdUdpSocket {
     fn bind(addrs: &[SocketAddr]) -> Result<Self, String> {
         let socket = StdUdpSocket::bind(addrs.iter().map(|s| s.to_string().parse().unwrap()).collect::<Vec<std::net::SocketAddr>>().as_slice());
-        if socket.is_err() {
-            return socket.map_err(|e| format!("{}", e))
-        } else {
-            let socket = socket.unwrap();
-            if let Err(e) = socket.set_nonblocking(true) {
-                return Err(format!("{}", e));
+        match socket {
+            Err(e) => Err(format!("{}", e)),
+            Ok(socket) => {
+                if let Err(e) = socket.set_nonblocking(true) {
+                    return Err(format!("{}", e));
+                }
+                Ok(socket)
             }
-            return Ok(socket);
         }
     }
 
@@ -58,8 +75,8 @@ impl SocketTrait for StdUdpSocket {
         StdUdpSocket::send_to(self, buf, addr).map_err(|e| e.to_string())
     }
 
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, String> {
-        StdUdpSocket::recv(self, buf).map_err(|e| e.to_string())
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), String> {
+        StdUdpSocket::recv_from(self, buf).map_err(|e| e.to_string())
     }
 
     fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), String> {
@@ -79,43 +96,51 @@ impl SocketTraitAsync for TokioUdpSocket {
     }
 
     async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, std::io::Error> {
-        TokioUdpSocket::send_to(&self, buf, addr).await
+        TokioUdpSocket::send_to(self, buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), std::io::Error> {
+        TokioUdpSocket::recv_from(self, buf).await
+    }
+}
+
+#[cfg(feature = "smol")]
+impl SocketTraitAsync for Async<StdUdpSocket> {
+    async fn bind(addr: &SocketAddr) -> Result<Self, std::io::Error> {
+        Async::<StdUdpSocket>::bind(*addr)
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, std::io::Error> {
+        Async::<StdUdpSocket>::send_to(self, buf, addr).await
     }
 
-    async fn recv(&self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        TokioUdpSocket::recv(self, buf).await
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), std::io::Error> {
+        Async::<StdUdpSocket>::recv_from(self, buf).await
     }
 }
 
 /// SocketTrait impl with an internal message queue for testing purposes
 pub struct MockSocket {
-    pub data: core::cell::RefCell<VecDeque<Vec<u8>>>,
+    /// Packets to be received, with the address they come from
+    pub data: core::cell::RefCell<VecDeque<(SocketAddr, Vec<u8>)>>,
+    /// Packets sent, with the address they were sent to
+    pub sent: core::cell::RefCell<VecDeque<(SocketAddr, Vec<u8>)>>,
 }
 
 impl SocketTrait for MockSocket {
     fn bind(_addrs: &[SocketAddr]) -> Result<Self, String> {
-        Ok(MockSocket{ data: core::cell::RefCell::new(VecDeque::new()) })
+        Ok(MockSocket{ data: core::cell::RefCell::new(VecDeque::new()), sent: core::cell::RefCell::new(VecDeque::new()) })
     }
 
-    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> Result<usize, String> {
-        let mut i: usize = 0; 
-
-        while i < buf.len() {
-            if buf[i] == 0 {
-                break;
-            }
-
-            i += 1;
-        }
-
-        Ok(i)
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, String> {
+        self.sent.borrow_mut().push_back((addr, buf.to_vec()));
+        Ok(buf.len())
     }
 
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, String> {
-        if self.data.borrow().len() > 0 {
-            let packet = self.data.borrow_mut().pop_front().unwrap();
-            buf.copy_from_slice(packet.as_slice());
-            return Ok(packet.len());
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), String> {
+        if let Some((addr, packet)) = self.data.borrow_mut().pop_front() {
+            buf[..packet.len()].copy_from_slice(packet.as_slice());
+            return Ok((packet.len(), addr));
         }
 
         Err("No packets".into())
@@ -152,8 +177,8 @@ impl SocketTrait for NullSocket {
         Ok(i)
     }
 
-    fn recv(&self, _buf: &mut [u8]) -> Result<usize, String> {
-        Ok(0)
+    fn recv_from(&self, _buf: &mut [u8]) -> Result<(usize, SocketAddr), String> {
+        Ok((0, SocketAddr::from(([0, 0, 0, 0], 0))))
     }
 
     fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), String> {
@@ -165,7 +190,7 @@ impl SocketTrait for NullSocket {
     }
 }
 
-#[cfg(feature = "tokio")]
+#[cfg(feature = "async")]
 impl SocketTraitAsync for NullSocket {
     async fn bind(_: &SocketAddr) -> Result<Self, std::io::Error> {
         Ok(NullSocket{})
@@ -185,7 +210,7 @@ impl SocketTraitAsync for NullSocket {
         Ok(i)
     }
 
-    async fn recv(&self, _: &mut [u8]) -> Result<usize, std::io::Error> {
-        Ok(0)
+    async fn recv_from(&self, _: &mut [u8]) -> Result<(usize, SocketAddr), std::io::Error> {
+        Ok((0, SocketAddr::from(([0, 0, 0, 0], 0))))
     }
 }
\ No newline at end of file
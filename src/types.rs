@@ -0,0 +1,118 @@
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Type of a method parameter or return value, as understood by the NetsBlox server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum IoTScapeType {
+    Number,
+    String,
+    Boolean,
+    /// List of values of the inner type, `list` on the wire is a list of `any`
+    List(Box<IoTScapeType>),
+    /// NetsBlox costume, also accepted as `costume` on the wire
+    Image,
+    Any,
+    /// Response is sent later as an event of the given type
+    Event(String),
+}
+
+impl fmt::Display for IoTScapeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoTScapeType::Number => f.write_str("number"),
+            IoTScapeType::String => f.write_str("string"),
+            IoTScapeType::Boolean => f.write_str("boolean"),
+            IoTScapeType::List(inner) if **inner == IoTScapeType::Any => f.write_str("list"),
+            IoTScapeType::List(inner) => write!(f, "list {}", inner),
+            IoTScapeType::Image => f.write_str("image"),
+            IoTScapeType::Any => f.write_str("any"),
+            IoTScapeType::Event(name) => write!(f, "event {}", name),
+        }
+    }
+}
+
+impl FromStr for IoTScapeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "number" => Ok(IoTScapeType::Number),
+            "string" => Ok(IoTScapeType::String),
+            "boolean" => Ok(IoTScapeType::Boolean),
+            "image" | "costume" => Ok(IoTScapeType::Image),
+            "any" => Ok(IoTScapeType::Any),
+            "list" => Ok(IoTScapeType::List(Box::new(IoTScapeType::Any))),
+            other => {
+                if let Some(inner) = other.strip_prefix("list ") {
+                    return Ok(IoTScapeType::List(Box::new(inner.parse()?)));
+                }
+
+                match other.strip_prefix("event ") {
+                    Some(name) if !name.trim().is_empty() => Ok(IoTScapeType::Event(name.trim().to_owned())),
+                    _ => Err("Unknown IoTScape type: ".to_owned() + other),
+                }
+            }
+        }
+    }
+}
+
+impl From<IoTScapeType> for String {
+    fn from(value: IoTScapeType) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for IoTScapeType {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl IoTScapeType {
+    /// Convert a value sent by NetsBlox into this type, e.g. the text "5" into the number 5
+    pub fn coerce(&self, value: &Value) -> Result<Value, String> {
+        match (self, value) {
+            (IoTScapeType::Number, Value::Number(_)) => Ok(value.clone()),
+            (IoTScapeType::Number, Value::String(s)) => {
+                let s = s.trim();
+                if let Ok(i) = s.parse::<i64>() {
+                    return Ok(i.into());
+                }
+
+                s.parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+                    .ok_or_else(|| format!("Expected a number, got \"{}\"", s))
+            }
+            (IoTScapeType::String, Value::String(_)) => Ok(value.clone()),
+            (IoTScapeType::String, Value::Number(n)) => Ok(n.to_string().into()),
+            (IoTScapeType::String, Value::Bool(b)) => Ok(b.to_string().into()),
+            (IoTScapeType::Boolean, Value::Bool(_)) => Ok(value.clone()),
+            (IoTScapeType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+                "true" => Ok(true.into()),
+                "false" => Ok(false.into()),
+                _ => Err(format!("Expected a boolean, got \"{}\"", s)),
+            },
+            (IoTScapeType::List(inner), Value::Array(items)) => items
+                .iter()
+                .map(|item| inner.coerce(item))
+                .collect::<Result<Vec<Value>, String>>()
+                .map(Value::Array),
+            (IoTScapeType::Image | IoTScapeType::Any | IoTScapeType::Event(_), _) => Ok(value.clone()),
+            (expected, _) => Err(format!("Expected {}, got {}", expected, value)),
+        }
+    }
+}
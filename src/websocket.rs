@@ -0,0 +1,403 @@
+//! Requests and responses over a WebSocket the device opens to the server, for networks where
+//! the server can't reach the device over UDP
+//!
+//! The NetsBlox server does not accept WebSocket connections from devices itself. This needs a
+//! relay on the server side that accepts the connection and passes every message on to the
+//! server's IoTScape UDP port, and every datagram back from it, as if it came from the device.
+//! Point the socket, or [`IoTScapeConfig::websocket_url`], at the relay.
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+};
+#[cfg(feature = "async")]
+use alloc::vec::Vec;
+use core::time::Duration;
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Instant,
+};
+
+use tungstenite::{
+    client::{uri_mode, IntoClientRequest},
+    handshake::HandshakeError,
+    stream::{MaybeTlsStream, Mode},
+    Message, WebSocket,
+};
+
+use crate::{config::IoTScapeConfig, socket::SocketTrait, Delivery, IoTScapeService, ServiceDefinition};
+#[cfg(feature = "async")]
+use crate::{socket::SocketTraitAsync, IoTScapeServiceAsync};
+
+/// Time to wait after failing to open a connection before trying again
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time to wait for a connection to open, and for reads and writes until timeouts are set
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest an async socket's read holds the connection, so sends don't wait long behind it
+#[cfg(feature = "async")]
+const READ_INTERVAL: Duration = Duration::from_millis(50);
+
+type Connection = WebSocket<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug)]
+struct State {
+    connection: Option<Connection>,
+    /// When to try connecting again after failing to, right away if not set
+    retry_at: Option<Instant>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+/// Socket sending and receiving datagrams as messages on a WebSocket to the server
+///
+/// Every datagram is one message, text if it is UTF-8, so the server end handles them as it would
+/// UDP datagrams. A dropped connection is opened again on a later send or receive, so keep
+/// announcing regularly for the server to learn the new connection.
+#[derive(Debug)]
+pub struct WsSocket {
+    url: String,
+    server: SocketAddr,
+    state: Mutex<State>,
+}
+
+impl WsSocket {
+    /// Connect to a `ws://` URL, or `wss://` with the `tls` feature
+    pub fn connect(url: &str) -> Result<Self, String> {
+        let connection = open(url, Some(TIMEOUT), Some(TIMEOUT))?;
+        let server = stream(&connection).peer_addr().map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            url: url.to_owned(),
+            server,
+            state: Mutex::new(State {
+                connection: Some(connection),
+                retry_at: None,
+                read_timeout: Some(TIMEOUT),
+                write_timeout: Some(TIMEOUT),
+            }),
+        })
+    }
+
+    /// Address of the server end, which requests are reported as coming from
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Whether the connection is open, it is opened again on the next send or receive if not
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connection.is_some()
+    }
+
+    /// Run an operation on the connection, opening it again first if it dropped
+    fn with_connection<T>(&self, op: impl FnOnce(&mut Connection) -> tungstenite::Result<T>) -> Result<T, String> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.connection.is_none() {
+            if state.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Err(format!("Not connected to {}", self.url));
+            }
+
+            let connection = open(&self.url, state.read_timeout, state.write_timeout)
+                .inspect_err(|_| state.retry_at = Some(Instant::now() + RECONNECT_DELAY))?;
+            state.retry_at = None;
+            log::info!("Reconnected to {}", self.url);
+            state.connection = Some(connection);
+        }
+
+        let connection = state.connection.as_mut().unwrap();
+        match op(connection) {
+            Ok(value) => Ok(value),
+            // Timeouts leave the connection usable
+            Err(tungstenite::Error::Io(e)) if is_timeout(&e) => Err(e.to_string()),
+            Err(e) => {
+                log::warn!("Lost connection to {}: {}", self.url, e);
+                state.connection = None;
+                Err(e.to_string())
+            }
+        }
+    }
+
+    /// Read the next datagram into `buf`, returning its size, or `None` if the read timed out
+    fn read_into(&self, buf: &mut [u8]) -> Result<Option<usize>, String> {
+        loop {
+            let message = self.with_connection(|ws| match ws.read() {
+                Ok(message) => Ok(Some(message)),
+                Err(tungstenite::Error::Io(e)) if is_timeout(&e) => Ok(None),
+                Err(e) => Err(e),
+            })?;
+            let data: &[u8] = match &message {
+                Some(Message::Text(text)) => text.as_bytes(),
+                Some(Message::Binary(data)) => data,
+                // Pings are answered by tungstenite, and a close ends the connection on the next read
+                Some(_) => continue,
+                None => return Ok(None),
+            };
+
+            if data.len() > buf.len() {
+                return Err(format!("Message of {} bytes is too large", data.len()));
+            }
+            buf[..data.len()].copy_from_slice(data);
+            return Ok(Some(data.len()));
+        }
+    }
+
+    /// Set a timeout on the connection's stream and remember it for later connections
+    fn set_timeout(&self, timeout: Option<Duration>, read: bool) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if read {
+            state.read_timeout = timeout;
+        } else {
+            state.write_timeout = timeout;
+        }
+
+        match state.connection.as_ref().map(stream) {
+            Some(stream) if read => stream.set_read_timeout(timeout).map_err(|e| e.to_string()),
+            Some(stream) => stream.set_write_timeout(timeout).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SocketTrait for WsSocket {
+    fn bind(_addrs: &[SocketAddr]) -> Result<Self, String> {
+        Err("WebSocket sockets are created by WsSocket::connect".to_owned())
+    }
+
+    // Everything goes to the server at the other end
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> Result<usize, String> {
+        let message = match core::str::from_utf8(buf) {
+            Ok(text) => Message::text(text),
+            Err(_) => Message::binary(buf.to_vec()),
+        };
+        self.with_connection(|ws| ws.send(message))?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), String> {
+        match self.read_into(buf)? {
+            Some(size) => Ok((size, self.server)),
+            None => Err(format!("Timed out reading from {}", self.url)),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), String> {
+        self.set_timeout(timeout, true)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), String> {
+        self.set_timeout(timeout, false)
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::WebSocket
+    }
+}
+
+/// Service talking to the server over a WebSocket
+pub type WsService = IoTScapeService<WsSocket>;
+
+impl WsService {
+    /// Connect to the server's WebSocket URL and create a service using the connection
+    pub fn connect(name: &str, definition: ServiceDefinition, url: &str) -> Result<Self, String> {
+        let socket = WsSocket::connect(url)?;
+        Ok(Self::with_socket(name, definition, socket.server(), socket))
+    }
+
+    /// Connect to the WebSocket URL of a config and create a service using the connection
+    pub fn connect_from_config(name: &str, definition: ServiceDefinition, config: &IoTScapeConfig) -> Result<Self, String> {
+        Self::connect(name, definition, websocket_url(config)?)
+    }
+}
+
+/// Read running on the thread pool, giving the datagram or `None` if it timed out
+#[cfg(feature = "async")]
+type PendingRead = blocking::Task<Result<Option<Vec<u8>>, String>>;
+
+/// Socket for [`IoTScapeServiceAsync`] sending and receiving datagrams as messages on a WebSocket
+/// to the server, on any async runtime
+///
+/// The connection is a [`WsSocket`] used from a thread pool. Reads give it up every
+/// `READ_INTERVAL` for sends to go through, and a read still going when its future is dropped is
+/// picked up by the next one, so no message is lost.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct WsSocketAsync {
+    socket: Arc<WsSocket>,
+    pending: futures::lock::Mutex<Option<PendingRead>>,
+}
+
+#[cfg(feature = "async")]
+impl WsSocketAsync {
+    /// Connect to a `ws://` URL, or `wss://` with the `tls` feature
+    pub async fn connect(url: &str) -> Result<Self, std::io::Error> {
+        let url = url.to_owned();
+        let socket = blocking::unblock(move || {
+            let socket = WsSocket::connect(&url)?;
+            socket.set_read_timeout(Some(READ_INTERVAL))?;
+            Ok(socket)
+        })
+        .await
+        .map_err(std::io::Error::other::<String>)?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            pending: futures::lock::Mutex::new(None),
+        })
+    }
+
+    /// Address of the server end, which requests are reported as coming from
+    pub fn server(&self) -> SocketAddr {
+        self.socket.server()
+    }
+
+    /// Whether the connection is open, it is opened again on the next send or receive if not
+    pub fn is_connected(&self) -> bool {
+        self.socket.is_connected()
+    }
+}
+
+#[cfg(feature = "async")]
+impl SocketTraitAsync for WsSocketAsync {
+    async fn bind(_addr: &SocketAddr) -> Result<Self, std::io::Error> {
+        Err(std::io::Error::other("WebSocket sockets are created by WsSocketAsync::connect"))
+    }
+
+    // Everything goes to the server at the other end
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, std::io::Error> {
+        let socket = self.socket.clone();
+        let buf = buf.to_vec();
+        blocking::unblock(move || socket.send_to(&buf, addr)).await.map_err(std::io::Error::other)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), std::io::Error> {
+        let mut pending = self.pending.lock().await;
+        loop {
+            let read = pending.get_or_insert_with(|| {
+                let socket = self.socket.clone();
+                let mut buf = alloc::vec![0; buf.len()];
+                blocking::unblock(move || {
+                    let size = socket.read_into(&mut buf)?;
+                    Ok(size.map(|size| {
+                        buf.truncate(size);
+                        buf
+                    }))
+                })
+            });
+            let result = read.await;
+            *pending = None;
+
+            match result {
+                Ok(Some(data)) if data.len() > buf.len() => {
+                    return Err(std::io::Error::other(format!("Message of {} bytes is too large", data.len())));
+                }
+                Ok(Some(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    return Ok((data.len(), self.socket.server()));
+                }
+                Ok(None) => {}
+                // Wait for the connection to be opened again by a later read
+                Err(e) if !self.socket.is_connected() => {
+                    log::debug!("{}", e);
+                    futures_timer::Delay::new(READ_INTERVAL).await;
+                }
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        }
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::WebSocket
+    }
+}
+
+/// Async service talking to the server over a WebSocket
+#[cfg(feature = "async")]
+pub type WsServiceAsync = IoTScapeServiceAsync<WsSocketAsync>;
+
+#[cfg(feature = "async")]
+impl WsServiceAsync {
+    /// Connect to the server's WebSocket URL and create a service using the connection
+    pub async fn connect(name: &str, definition: ServiceDefinition, url: &str) -> Result<Self, std::io::Error> {
+        let socket = WsSocketAsync::connect(url).await?;
+        Ok(Self::with_socket(name, definition, socket.server(), socket))
+    }
+
+    /// Connect to the WebSocket URL of a config and create a service using the connection
+    pub async fn connect_from_config(name: &str, definition: ServiceDefinition, config: &IoTScapeConfig) -> Result<Self, std::io::Error> {
+        let url = websocket_url(config).map_err(std::io::Error::other)?;
+        Self::connect(name, definition, url).await
+    }
+}
+
+/// The WebSocket URL of a config, which has none unless one is set
+fn websocket_url(config: &IoTScapeConfig) -> Result<&str, String> {
+    config.websocket_url.as_deref().ok_or_else(|| "No WebSocket URL configured".to_owned())
+}
+
+/// Whether an error is a read or write timing out, which leaves the connection usable
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Open a connection to `url`, giving up if connecting or the handshake takes longer than
+/// `TIMEOUT`, then use the given timeouts on its stream
+fn open(url: &str, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> Result<Connection, String> {
+    let error = |e: &dyn core::fmt::Display| format!("Could not connect to {}: {}", url, e);
+
+    let request = url.into_client_request().map_err(|e| error(&e))?;
+    let mode = uri_mode(request.uri()).map_err(|e| error(&e))?;
+    let host = request.uri().host().ok_or_else(|| error(&"No host name"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = request.uri().port_u16().unwrap_or(match mode {
+        Mode::Plain => 80,
+        Mode::Tls => 443,
+    });
+
+    let mut last_error = error(&"No addresses found");
+    let tcp = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| error(&e))?
+        .find_map(|addr| TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| last_error = error(&e)).ok())
+        .ok_or(last_error)?;
+
+    tcp.set_nodelay(true).map_err(|e| error(&e))?;
+    tcp.set_read_timeout(Some(TIMEOUT)).map_err(|e| error(&e))?;
+    tcp.set_write_timeout(Some(TIMEOUT)).map_err(|e| error(&e))?;
+
+    #[cfg(feature = "tls")]
+    let handshake = tungstenite::client_tls(request, tcp);
+    #[cfg(not(feature = "tls"))]
+    let handshake = match mode {
+        Mode::Plain => tungstenite::client(request, MaybeTlsStream::Plain(tcp)),
+        Mode::Tls => return Err(error(&"wss:// needs the tls feature")),
+    };
+
+    let connection = match handshake {
+        Ok((connection, _)) => connection,
+        Err(HandshakeError::Failure(e)) => return Err(error(&e)),
+        // The stream blocks, so only timing out interrupts the handshake
+        Err(HandshakeError::Interrupted(_)) => return Err(error(&"Timed out")),
+    };
+
+    let tcp = stream(&connection);
+    tcp.set_read_timeout(read_timeout).map_err(|e| error(&e))?;
+    tcp.set_write_timeout(write_timeout).map_err(|e| error(&e))?;
+    Ok(connection)
+}
+
+/// The TCP stream under a connection, for setting timeouts
+fn stream(connection: &Connection) -> &TcpStream {
+    match connection.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream,
+        #[cfg(feature = "tls")]
+        MaybeTlsStream::Rustls(stream) => stream.get_ref(),
+        _ => unreachable!("Connections are only opened by open"),
+    }
+}
@@ -1,10 +1,1306 @@
 #[cfg(test)]
 mod tests {
-    //use iotscape::*;
+    use std::collections::BTreeMap;
 
-    // #[test]
-    // fn it_works() {
-    //     let result = add(2, 2);
-    //     assert_eq!(result, 4);
-    // }
+    use iotscape::*;
+
+    fn example_definition() -> ServiceDefinition {
+        let mut definition = ServiceDefinition {
+            id: "rs1".to_owned(),
+            methods: BTreeMap::new(),
+            events: BTreeMap::new(),
+            description: IoTScapeServiceDescription {
+                description: Some("Test IoTScape service.".to_owned()),
+                contact: Some("gstein@ltu.edu".to_owned()),
+                version: "1".to_owned(),
+                ..Default::default()
+            },
+        };
+
+        definition.methods.insert(
+            "add".to_owned(),
+            MethodDescription {
+                documentation: Some("Adds two numbers".to_owned()),
+                params: vec![
+                    MethodParam {
+                        name: "a".to_owned(),
+                        documentation: Some("First number".to_owned()),
+                        r#type: IoTScapeType::Number,
+                        optional: false,
+                    },
+                    MethodParam {
+                        name: "b".to_owned(),
+                        documentation: None,
+                        r#type: IoTScapeType::Number,
+                        optional: true,
+                    },
+                ],
+                returns: MethodReturns {
+                    documentation: None,
+                    r#type: vec![IoTScapeType::Number],
+                },
+                timeout: None,
+            },
+        );
+        definition.methods.insert(
+            "timer".to_owned(),
+            MethodDescription {
+                documentation: None,
+                params: vec![],
+                returns: MethodReturns {
+                    documentation: Some("Response after delay".to_owned()),
+                    r#type: vec![IoTScapeType::Event("timer".to_owned())],
+                },
+                timeout: None,
+            },
+        );
+        definition.events.insert("timer".to_owned(), EventDescription { params: vec![] });
+
+        definition
+    }
+
+    #[test]
+    fn announce_snapshot() {
+        assert_eq!(
+            example_definition().announcement("ExampleService"),
+            concat!(
+                r#"{"ExampleService":{"id":"rs1","methods":{"#,
+                r#""add":{"documentation":"Adds two numbers","params":["#,
+                r#"{"name":"a","documentation":"First number","type":"number","optional":false},"#,
+                r#"{"name":"b","type":"number","optional":true}],"returns":{"type":["number"]}},"#,
+                r#""timer":{"params":[],"returns":{"documentation":"Response after delay","type":["event timer"]}}},"#,
+                r#""events":{"timer":{"params":[]}},"#,
+                r#""service":{"description":"Test IoTScape service.","contact":"gstein@ltu.edu","version":"1"}}}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn announce_lite_snapshot() {
+        assert_eq!(
+            example_definition().lite().announcement("ExampleService"),
+            r#"{"ExampleService":{"id":"rs1","methods":{},"events":{},"service":{"version":"1"}}}"#
+        );
+    }
+
+    #[test]
+    fn definition_round_trip() {
+        let json = example_definition().announcement("ExampleService");
+        let parsed: BTreeMap<String, ServiceDefinition> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["ExampleService"].announcement("ExampleService"), json);
+    }
+
+    #[test]
+    fn unknown_type_rejected() {
+        let param = r#"{"name":"a","type":"nubmer","optional":false}"#;
+        assert!(serde_json::from_str::<MethodParam>(param).is_err());
+    }
+
+    #[test]
+    fn event_response_snapshot() {
+        let response = Response {
+            id: "rs1".to_owned(),
+            request: "5".to_owned(),
+            service: "ExampleService".to_owned(),
+            response: None,
+            event: Some(EventResponse {
+                r#type: "timer".to_owned(),
+                args: BTreeMap::from([("ms".to_owned(), "10".to_owned())]),
+            }),
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"id":"rs1","request":"5","service":"ExampleService","event":{"type":"timer","args":{"ms":"10"}}}"#
+        );
+    }
+
+    #[test]
+    fn request_without_client_id() {
+        let request: Request = serde_json::from_str(
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#,
+        )
+        .unwrap();
+        assert!(request.client_id.is_none());
+        assert!(!serde_json::to_string(&request).unwrap().contains("clientId"));
+    }
+
+    #[test]
+    fn request_ref_borrows_from_datagram() {
+        let content = br#"{"id":"7","service":"ExampleService","device":"rs1","function":"add","params":[1,"2"],"clientId":"c1"}"#;
+        let request = RequestRef::parse(content).unwrap();
+        assert!(matches!(request.function, std::borrow::Cow::Borrowed("add")));
+        assert_eq!(request.params.unwrap().get(), r#"[1,"2"]"#);
+
+        let request = request.into_owned().unwrap();
+        assert_eq!(request.id, "7");
+        assert_eq!(request.params, vec![serde_json::json!(1), serde_json::json!("2")]);
+        assert_eq!(request.client_id.as_deref(), Some("c1"));
+
+        let heartbeat = RequestRef::parse(br#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat"}"#).unwrap();
+        assert!(heartbeat.into_owned().unwrap().params.is_empty());
+    }
+
+    #[test]
+    fn list_type_wire_format() {
+        let list: IoTScapeType = "list number".parse().unwrap();
+        assert_eq!(list, IoTScapeType::List(Box::new(IoTScapeType::Number)));
+        assert_eq!(list.to_string(), "list number");
+        assert_eq!("costume".parse::<IoTScapeType>().unwrap(), IoTScapeType::Image);
+        assert_eq!(serde_json::to_string(&IoTScapeType::List(Box::new(IoTScapeType::Any))).unwrap(), r#""list""#);
+    }
+
+    #[test]
+    fn into_netsblox_values() {
+        use serde_json::json;
+        use std::collections::HashMap;
+
+        assert_eq!((1, "a", true).into_netsblox(), json!([1, "a", true]));
+        assert_eq!(((1.5, 'x'),).into_netsblox(), json!([[1.5, "x"]]));
+        assert_eq!(vec![Some(1), None].into_netsblox(), json!([1, ""]));
+        assert_eq!(None::<String>.into_netsblox(), json!(""));
+
+        // Maps become tables of [key, value] rows
+        let map = BTreeMap::from([("b", vec![2, 3]), ("a", vec![1])]);
+        assert_eq!(map.into_netsblox(), json!([["a", [1]], ["b", [2, 3]]]));
+        let map = HashMap::from([("only".to_owned(), Some(1.0))]);
+        assert_eq!(map.into_netsblox(), json!([["only", 1.0]]));
+
+        // Responses hold the value as their one item, or nothing for ()
+        assert_eq!((1, 2).into_response(), vec![json!([1, 2])]);
+        assert_eq!("ok".into_response(), vec![json!("ok")]);
+        assert!(().into_response().is_empty());
+    }
+
+    #[test]
+    fn validate_request_coerces_params() {
+        let definition = example_definition();
+        let mut request: Request = serde_json::from_str(
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":["2", 3.5]}"#,
+        )
+        .unwrap();
+        definition.validate_request(&mut request).unwrap();
+        assert_eq!(request.params, vec![serde_json::json!(2), serde_json::json!(3.5)]);
+
+        request.params = vec!["two".into()];
+        assert!(definition.validate_request(&mut request).is_err());
+
+        // Blank optional params are missing, blank required ones are not
+        request.params = vec![1.into(), "".into()];
+        definition.validate_request(&mut request).unwrap();
+        assert_eq!(request.params, vec![serde_json::json!(1), serde_json::Value::Null]);
+
+        request.params = vec!["".into(), 1.into()];
+        assert!(definition.validate_request(&mut request).is_err());
+
+        request.params = vec![];
+        assert!(definition.validate_request(&mut request).is_err());
+
+        request.function = "subtract".to_owned();
+        assert!(definition.validate_request(&mut request).is_err());
+
+        request.function = "_requestedKey".to_owned();
+        assert!(definition.validate_request(&mut request).is_ok());
+    }
+
+    #[test]
+    fn builder_helpers() {
+        let built = ServiceDefinition::new(
+            "rs1",
+            IoTScapeServiceDescription {
+                description: Some("Test IoTScape service.".to_owned()),
+                contact: Some("gstein@ltu.edu".to_owned()),
+                version: "1".to_owned(),
+                ..Default::default()
+            },
+        )
+        .with_method(
+            "add",
+            MethodDescription::new("Adds two numbers")
+                .with_param("a", IoTScapeType::Number, "First number")
+                .with_optional_param("b", IoTScapeType::Number, "")
+                .with_return(IoTScapeType::Number),
+        )
+        .with_event("timer", &[]);
+
+        assert_eq!(built.methods["add"].params.len(), 2);
+        assert!(built.methods["add"].params[1].optional);
+        assert!(built.events.contains_key("timer"));
+    }
+
+    #[cfg(feature = "costume")]
+    #[test]
+    fn costume_xml() {
+        let png = b"\x89PNG\r\n\x1a\nabc".to_vec();
+        let costume = Costume::from_bytes(png).unwrap().with_name("a \"b\"").with_center(1.5, 2.0);
+        assert_eq!(
+            serde_json::Value::from(costume),
+            serde_json::Value::String(
+                r#"<costume name="a &quot;b&quot;" collabId="" center-x="1.5" center-y="2" image="data:image/png;base64,iVBORw0KGgphYmM="/>"#.to_owned()
+            )
+        );
+        assert!(Costume::from_bytes(b"GIF89a".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "security")]
+    #[test]
+    fn security_key_lifecycle() {
+        use iotscape::security::{KeyState, Security};
+
+        let mut security = Security::default();
+        security.set_requested();
+        assert_eq!(security.state(), &KeyState::Requested);
+
+        security.accept_key(&[serde_json::json!("1, 2, 3")]).unwrap();
+        assert_eq!(security.key(), Some(&[1u8, 2, 3][..]));
+        assert_eq!(security.encrypt("abc~"), "bdf ");
+        // Shifts wrap around within printable ASCII, other characters keep their place in the key
+        assert_eq!(security.encrypt("a~\né!"), "b!\né#");
+        assert_eq!(security.decrypt("b!\né#"), "a~\né!");
+
+        let mut values = vec![serde_json::json!(["Hello", 5]), serde_json::json!("World")];
+        security.encrypt_values(&mut values);
+        assert_ne!(values[1], serde_json::json!("World"));
+        security.decrypt_values(&mut values);
+        assert_eq!(values, vec![serde_json::json!(["Hello", 5]), serde_json::json!("World")]);
+
+        security.accept_key(&[serde_json::json!([4, 5])]).unwrap();
+        assert_eq!(security.key(), Some(&[4u8, 5][..]));
+        assert!(security.accept_key(&[serde_json::json!(300)]).is_err());
+
+        security.reset();
+        assert_eq!(security.encrypt("abc"), "abc");
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signed_request_round_trip() {
+        use iotscape::signing::{SignatureError, Signer};
+
+        let request: Request = serde_json::from_str(
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2],"clientId":"c1"}"#,
+        )
+        .unwrap();
+
+        let signer = Signer::new(b"secret");
+        let signed = signer.sign_request(&request);
+        let verified = signer.verify_request(signed.as_bytes()).unwrap();
+        assert_eq!(verified.function, "add");
+        assert_eq!(verified.client_id.as_deref(), Some("c1"));
+
+        let tampered = signed.replace(r#""params":[1,2]"#, r#""params":[1,3]"#);
+        assert_eq!(Signer::new(b"secret").verify_request(tampered.as_bytes()).unwrap_err(), SignatureError::Invalid);
+        assert_eq!(Signer::new(b"other").verify_request(signed.as_bytes()).unwrap_err(), SignatureError::Invalid);
+
+        let unsigned = serde_json::to_string(&request).unwrap();
+        assert_eq!(signer.verify_request(unsigned.as_bytes()).unwrap_err(), SignatureError::Missing);
+
+        // Each signed request is accepted once, and only while fresh
+        assert_eq!(signer.verify_request(signed.as_bytes()).unwrap_err(), SignatureError::Replayed);
+        let stale = Signer::new(b"secret").with_max_age(std::time::Duration::ZERO);
+        let signed = stale.sign_request(&request);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(stale.verify_request(signed.as_bytes()).unwrap_err(), SignatureError::Replayed);
+
+        // Signatures cover the bytes as sent, whatever their key order and number formatting
+        let reordered = format!(r#"{{"params":[1.0e0,2],"id":"1","service":"ExampleService","device":"rs1","function":"add","timestamp":{},"nonce":"n1"}}"#,
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+        let signature = {
+            use hmac::Mac;
+            let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(reordered.as_bytes());
+            mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        };
+        let signed = format!(r#"{},"signature":"{}"}}"#, &reordered[..reordered.len() - 1], signature);
+        assert_eq!(signer.verify_request(signed.as_bytes()).unwrap().params, vec![serde_json::json!(1.0), 2.into()]);
+
+        // Responses are signed into the caller's buffer, replacing what it held
+        let mut buf = Vec::with_capacity(1024);
+        buf.extend_from_slice(b"leftover");
+        let response = Response {
+            id: "rs1".to_owned(),
+            request: "1".to_owned(),
+            service: "ExampleService".to_owned(),
+            response: Some(vec![3.into()]),
+            event: None,
+            error: None,
+        };
+        signer.sign_response(&response, &mut buf);
+        assert_eq!(buf.capacity(), 1024);
+        signer.verify(&buf).unwrap();
+        assert_eq!(serde_json::from_slice::<Response>(&buf).unwrap().response, Some(vec![3.into()]));
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn unsigned_and_tampered_requests_dropped() {
+        use iotscape::signing::Signer;
+        use std::sync::atomic::Ordering;
+
+        let signer = Signer::new(b"secret");
+        let mut service = mock_service();
+        service.signer = Some(signer.clone());
+        let request: Request = serde_json::from_str(
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+        )
+        .unwrap();
+        let signed = signer.sign_request(&request);
+
+        push_request(&service, "10.0.0.1:1978", &serde_json::to_string(&request).unwrap());
+        push_request(&service, "10.0.0.1:1978", &signed.replace(r#""params":[1,2]"#, r#""params":[1,3]"#));
+        push_request(&service, "10.0.0.1:1978", &signed);
+        push_request(&service, "10.0.0.1:1978", &signed);
+        service.poll(None);
+
+        assert_eq!(service.rx_queue.len(), 1);
+        assert_eq!(service.rx_queue[0].params, vec![serde_json::json!(1), serde_json::json!(2)]);
+        assert_eq!(service.stats.unsigned.load(Ordering::Relaxed), 1);
+        assert_eq!(service.stats.bad_signature.load(Ordering::Relaxed), 1);
+        assert_eq!(service.stats.replayed.load(Ordering::Relaxed), 1);
+        assert!(sent_responses(&service).is_empty());
+    }
+
+    fn mock_service() -> IoTScapeService<socket::MockSocket> {
+        IoTScapeService::new("ExampleService", example_definition(), "10.0.0.1:1978".parse().unwrap())
+    }
+
+    fn push_request(service: &IoTScapeService<socket::MockSocket>, from: &str, request: &str) {
+        service
+            .socket()
+            .data
+            .borrow_mut()
+            .push_back((from.parse().unwrap(), request.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn requests_from_unknown_sources_dropped() {
+        let mut service = mock_service();
+        let request = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+
+        // Only the server's own port counts as the server
+        push_request(&service, "10.0.0.1:1978", request);
+        push_request(&service, "10.0.0.1:1979", request);
+        push_request(&service, "10.0.0.66:1978", request);
+        service.poll(None);
+        assert_eq!(service.rx_queue.len(), 1);
+        assert_eq!(service.stats.bad_source.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        service.allowed_sources.push("10.0.0.66".parse().unwrap());
+        push_request(&service, "10.0.0.66:5000", request);
+        service.poll(None);
+        assert_eq!(service.rx_queue.len(), 2);
+    }
+
+    fn sent_responses(service: &IoTScapeService<socket::MockSocket>) -> Vec<Response> {
+        service
+            .socket()
+            .sent
+            .borrow_mut()
+            .drain(..)
+            .map(|(_, packet)| serde_json::from_slice(&packet).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rate_limited_clients_get_errors() {
+        use iotscape::ratelimit::{RateLimit, RateLimiter};
+
+        let mut service = mock_service();
+        service.rate_limiter = Some(RateLimiter::new(RateLimit::new(2, 0.0)));
+
+        for id in 0..3 {
+            push_request(&service, "10.0.0.1:1978", &format!(
+                r#"{{"id":"{}","service":"ExampleService","device":"rs1","function":"add","params":[1,2],"clientId":"a"}}"#, id
+            ));
+        }
+        push_request(&service, "10.0.0.1:1978",
+            r#"{"id":"3","service":"ExampleService","device":"rs1","function":"add","params":[1,2],"clientId":"b"}"#);
+        service.poll(None);
+
+        assert_eq!(service.rx_queue.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), vec!["0", "1", "3"]);
+        let sent = sent_responses(&service);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].request, "2");
+        assert_eq!(sent[0].error.as_deref(), Some("Rate limited"));
+        assert_eq!(service.stats.rate_limited.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // Requests without a client id share a bucket
+        service.rx_queue.clear();
+        for id in 4..7 {
+            push_request(&service, "10.0.0.1:1978", &format!(
+                r#"{{"id":"{}","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}}"#, id
+            ));
+        }
+        service.poll(None);
+        assert_eq!(service.rx_queue.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), vec!["4", "5"]);
+        assert_eq!(sent_responses(&service)[0].request, "6");
+    }
+
+    #[test]
+    fn access_control_rejects_clients() {
+        use iotscape::access::{AccessControl, AccessRule};
+
+        let mut service = mock_service();
+        service.access_control = Some(
+            AccessControl::new()
+                .with_rule("timer", AccessRule::allow(&["instructor"]))
+                .with_hook(|request| request.client_id.as_deref() != Some("banned")),
+        );
+
+        for (id, function, client) in [("0", "timer", "instructor"), ("1", "timer", "student"), ("2", "add", "student"), ("3", "add", "banned")] {
+            push_request(&service, "10.0.0.1:1978", &format!(
+                r#"{{"id":"{}","service":"ExampleService","device":"rs1","function":"{}","params":[],"clientId":"{}"}}"#, id, function, client
+            ));
+        }
+        service.poll(None);
+
+        assert_eq!(service.rx_queue.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), vec!["0", "2"]);
+        let sent = sent_responses(&service);
+        assert_eq!(sent.iter().map(|r| r.request.as_str()).collect::<Vec<_>>(), vec!["1", "3"]);
+        assert!(sent.iter().all(|r| r.error.as_deref() == Some("Access denied")));
+        assert!(!AccessRule::allow(&["instructor"]).permits(None));
+        assert!(AccessRule::deny(&["banned"]).permits(None));
+    }
+
+    #[test]
+    fn retransmitted_requests_handled_once() {
+        use iotscape::dedup::ResponseCache;
+
+        let mut service = mock_service();
+        service.response_cache = Some(ResponseCache::new(std::time::Duration::from_secs(60)));
+        let request = r#"{"id":"7","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+
+        push_request(&service, "10.0.0.1:1978", request);
+        push_request(&service, "10.0.0.1:1978", request);
+        service.poll(None);
+        assert_eq!(service.rx_queue.len(), 1);
+        assert!(sent_responses(&service).is_empty());
+
+        let next_msg = service.rx_queue.pop_front().unwrap();
+        service.respond(next_msg, Ok(vec![3.into()])).unwrap();
+        assert_eq!(sent_responses(&service).len(), 1);
+
+        push_request(&service, "10.0.0.1:1978", request);
+        service.poll(None);
+        assert!(service.rx_queue.is_empty());
+        let resent = sent_responses(&service);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].response, Some(vec![3.into()]));
+        assert_eq!(service.stats.duplicates.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        // Requests that could not be read are forgotten, so a retransmission is handled
+        push_request(&service, "10.0.0.1:1978", r#"{"id":"8","service":"ExampleService","device":"rs1","function":"add","params":5}"#);
+        service.poll(None);
+        assert!(service.rx_queue.is_empty());
+        push_request(&service, "10.0.0.1:1978", &request.replace(r#""id":"7""#, r#""id":"8""#));
+        service.poll(None);
+        assert_eq!(service.rx_queue.pop_front().unwrap().id, "8");
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let mut service = mock_service();
+        service.definition.methods.get_mut("add").unwrap().timeout = Some(std::time::Duration::ZERO);
+        push_request(&service, "10.0.0.1:1978", r#"{"id":"4","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#);
+        service.poll(None);
+
+        let sent = sent_responses(&service);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].request, "4");
+        assert_eq!(sent[0].error.as_deref(), Some(iotscape::TIMEOUT_ERROR));
+        assert_eq!(service.stats.timed_out.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let next_msg = service.rx_queue.pop_front().unwrap();
+        assert!(service.respond(next_msg, Ok(vec![3.into()])).is_err());
+        service.poll(None);
+        assert!(sent_responses(&service).is_empty());
+    }
+
+    #[test]
+    fn shutdown_drains_queue_and_notifies_server() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+        let mut service: IoTScapeService = IoTScapeService::new("ExampleService", example_definition(), server.local_addr().unwrap());
+
+        service.rx_queue.push_back(serde_json::from_str(
+            r#"{"id":"5","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+        ).unwrap());
+        service.tx_queue.push_back(Response {
+            id: "rs1".to_owned(),
+            request: "4".to_owned(),
+            service: "ExampleService".to_owned(),
+            response: Some(vec![3.into()]),
+            event: None,
+            error: None,
+        });
+        service.shutdown(std::time::Duration::from_secs(1), true).unwrap();
+
+        let mut buf = [0u8; 65_535];
+        let received: Vec<Response> = (0..3)
+            .map(|_| {
+                let (size, _) = server.recv_from(&mut buf).unwrap();
+                serde_json::from_slice(&buf[..size]).unwrap()
+            })
+            .collect();
+        assert_eq!(received[0].request, "4");
+        assert_eq!(received[1].request, "5");
+        assert_eq!(received[1].error.as_deref(), Some(SHUTDOWN_ERROR));
+        assert_eq!(received[2].event.as_ref().unwrap().r#type, DISCONNECT_EVENT);
+    }
+
+    #[test]
+    fn host_routes_requests_by_device() {
+        use iotscape::host::IoTScapeHost;
+
+        let mut host: IoTScapeHost<socket::MockSocket> = IoTScapeHost::new("10.0.0.1:1978".parse().unwrap());
+        for device in ["rs1", "rs2"] {
+            let mut definition = example_definition();
+            definition.id = device.to_owned();
+            host.add_service("ExampleService", definition);
+        }
+        host.announce_all().unwrap();
+        assert_eq!(host.socket().sent.borrow_mut().drain(..).count(), 2);
+
+        for (id, device) in [("1", "rs2"), ("2", "rs1"), ("3", "rs3"), ("4", "rs2")] {
+            host.socket().data.borrow_mut().push_back(("10.0.0.1:1978".parse().unwrap(), format!(
+                r#"{{"id":"{}","service":"ExampleService","device":"{}","function":"add","params":[1,2]}}"#, id, device
+            ).into_bytes()));
+        }
+        host.poll(None);
+
+        let queued = |host: &IoTScapeHost<socket::MockSocket>, device| {
+            host.service("ExampleService", device).unwrap().rx_queue.iter().map(|r| r.id.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(queued(&host, "rs1"), vec!["2"]);
+        assert_eq!(queued(&host, "rs2"), vec!["1", "4"]);
+
+        let service = host.service_mut("ExampleService", "rs2").unwrap();
+        let next_msg = service.rx_queue.pop_front().unwrap();
+        service.respond(next_msg, Ok(vec![3.into()])).unwrap();
+        let (_, packet) = host.socket().sent.borrow_mut().pop_front().unwrap();
+        let response: Response = serde_json::from_slice(&packet).unwrap();
+        assert_eq!((response.id.as_str(), response.request.as_str()), ("rs2", "1"));
+    }
+
+    #[test]
+    fn definition_changes_are_announced() {
+        let mut service = mock_service();
+        service.announce().unwrap();
+        service.auto_announce = true;
+
+        service.add_method("reset", MethodDescription::new("Resets the device")).unwrap();
+        assert!(service.remove_method("missing").unwrap().is_none());
+        service
+            .update_description(IoTScapeServiceDescription {
+                version: "2".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(service.remove_method("timer").unwrap().is_some());
+
+        let announcements: Vec<serde_json::Value> = service
+            .socket()
+            .sent
+            .borrow_mut()
+            .drain(..)
+            .map(|(_, packet)| serde_json::from_slice(&packet).unwrap())
+            .collect();
+        assert_eq!(announcements.len(), 4);
+        assert!(announcements[0]["ExampleService"]["methods"].get("reset").is_none());
+        assert!(announcements[1]["ExampleService"]["methods"].get("reset").is_some());
+        assert_eq!(announcements[2]["ExampleService"]["service"]["version"], "2");
+        assert!(announcements[3]["ExampleService"]["methods"].get("timer").is_none());
+    }
+
+    #[test]
+    fn sessions_per_client() {
+        use iotscape::session::SessionStore;
+
+        let mut service = mock_service();
+        service.sessions = Some(SessionStore::new(std::time::Duration::from_millis(50)));
+        for (id, client) in [("1", "alice"), ("2", "bob"), ("3", "alice")] {
+            push_request(&service, "10.0.0.1:1978", &format!(
+                r#"{{"id":"{}","service":"ExampleService","device":"rs1","function":"add","params":[1,2],"clientId":"{}"}}"#, id, client
+            ));
+        }
+        service.poll(None);
+
+        let sessions = service.sessions.as_ref().unwrap();
+        while let Some(request) = service.rx_queue.pop_front() {
+            sessions.with(request.client_id.as_deref().unwrap(), |calls: &mut Vec<String>| calls.push(request.id));
+        }
+        assert_eq!(sessions.get::<Vec<String>>("alice"), Some(vec!["1".to_owned(), "3".to_owned()]));
+        assert_eq!(sessions.get::<Vec<String>>("bob"), Some(vec!["2".to_owned()]));
+        assert_eq!(sessions.get::<u32>("bob"), None);
+        assert_eq!(sessions.with("bob", |count: &mut u32| { *count += 1; *count }), 1);
+        // Values of different types sit side by side
+        assert_eq!(sessions.get::<Vec<String>>("bob"), Some(vec!["2".to_owned()]));
+        sessions.insert("bob", 5u32);
+        assert_eq!(sessions.with("bob", |calls: &mut Vec<String>| calls.len()), 1);
+        assert_eq!(sessions.get::<u32>("bob"), Some(5));
+
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        push_request(&service, "10.0.0.1:1978", r#"{"id":"4","service":"ExampleService","device":"rs1","function":"add","params":[1,2],"clientId":"alice"}"#);
+        service.poll(None);
+        let sessions = service.sessions.as_ref().unwrap();
+        assert!(sessions.get::<Vec<String>>("alice").is_some());
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[test]
+    fn config_from_presets_files_and_env() {
+        use iotscape::config::IoTScapeConfig;
+
+        let local = IoTScapeConfig::local();
+        assert_eq!(local.server_addr().unwrap(), "127.0.0.1:1978".parse().unwrap());
+        assert_eq!(IoTScapeConfig::preset("netsblox").unwrap(), IoTScapeConfig::default());
+        assert!(IoTScapeConfig::preset("staging").is_err());
+        let named = local.clone().with_server("localhost:1978").server_addr().unwrap();
+        assert!(named.ip().is_loopback() && named.port() == 1978);
+
+        let path = std::env::temp_dir().join(format!("iotscape-config-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"preset":"local","server":"127.0.0.1:2000"}"#).unwrap();
+        let from_file = IoTScapeConfig::netsblox().with_file(&path).unwrap();
+        assert_eq!(from_file, local.clone().with_server("127.0.0.1:2000"));
+        std::fs::write(&path, r#"{"sever":"127.0.0.1:2000"}"#).unwrap();
+        assert!(IoTScapeConfig::netsblox().with_file(&path).is_err());
+
+        // The preset comes first, then the file, then single settings
+        std::fs::write(&path, r#"{"server":"127.0.0.1:2000"}"#).unwrap();
+        std::env::set_var("IOTSCAPE_PRESET", "local");
+        std::env::set_var("IOTSCAPE_CONFIG", &path);
+        std::env::set_var("IOTSCAPE_RESPONSE_ENDPOINT", "http://127.0.0.1:9000/response");
+        std::env::set_var("IOTSCAPE_FALLBACK_SERVERS", "127.0.0.1:2001, localhost:2002");
+        std::env::set_var("IOTSCAPE_WEBSOCKET_URL", "ws://127.0.0.1:9001/iotscape");
+        let loaded = IoTScapeConfig::load();
+        for var in [
+            "IOTSCAPE_PRESET",
+            "IOTSCAPE_CONFIG",
+            "IOTSCAPE_RESPONSE_ENDPOINT",
+            "IOTSCAPE_FALLBACK_SERVERS",
+            "IOTSCAPE_WEBSOCKET_URL",
+        ] {
+            std::env::remove_var(var);
+        }
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.server, "127.0.0.1:2000");
+        assert_eq!(loaded.announce_url, local.announce_url);
+        assert_eq!(loaded.response_url, "http://127.0.0.1:9000/response");
+        assert_eq!(loaded.fallback_servers, ["127.0.0.1:2001", "localhost:2002"]);
+        assert_eq!(loaded.websocket_url.as_deref(), Some("ws://127.0.0.1:9001/iotscape"));
+        assert_eq!(local.websocket_url, None);
+        assert_eq!(local.clone().with_websocket_url("ws://relay/iotscape").websocket_url.as_deref(), Some("ws://relay/iotscape"));
+
+        let service = IoTScapeService::<socket::MockSocket>::from_config("ExampleService", example_definition(), &loaded).unwrap();
+        assert_eq!(service.name, "ExampleService");
+        let resolver = service.resolver.as_ref().unwrap();
+        assert_eq!(resolver.servers(), ["127.0.0.1:2000", "127.0.0.1:2001", "localhost:2002"]);
+        assert_eq!(resolver.server_timeout, Some(iotscape::resolve::DEFAULT_SERVER_TIMEOUT));
+        assert_eq!(local.resolver().server_timeout, None);
+        #[cfg(all(feature = "http", feature = "reqwest"))]
+        {
+            let http = service.http.as_ref().unwrap().config();
+            assert_eq!(http.announce_url(), local.announce_url);
+            assert_eq!(http.response_url(), "http://127.0.0.1:9000/response");
+        }
+        assert!(IoTScapeService::<socket::MockSocket>::from_config(
+            "ExampleService",
+            example_definition(),
+            &local.with_server("no-port"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn silent_server_fails_over_and_reannounces() {
+        use iotscape::resolve::ServerResolver;
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let primary = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fallback = UdpSocket::bind("127.0.0.1:0").unwrap();
+        fallback.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let primary_addr = primary.local_addr().unwrap();
+        let fallback_addr = fallback.local_addr().unwrap();
+
+        let mut service = IoTScapeServiceUdp::new("ExampleService", example_definition(), primary_addr);
+        service.resolver = Some(
+            ServerResolver::new(&primary_addr.to_string())
+                .with_fallback(&fallback_addr.to_string())
+                .with_server_timeout(Duration::from_millis(100))
+                .with_max_failures(1),
+        );
+
+        // Nothing from the primary yet, so the service keeps talking to it
+        service.poll(Some(Duration::from_millis(5)));
+        assert_eq!(service.server(), primary_addr);
+
+        // The lookup runs on its own thread, so the change shows up on a later poll
+        std::thread::sleep(Duration::from_millis(110));
+        for _ in 0..200 {
+            service.poll(Some(Duration::from_millis(5)));
+            if service.server() != primary_addr {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(service.server(), fallback_addr);
+        assert_eq!(service.resolver.as_ref().unwrap().current(), fallback_addr.to_string());
+
+        let mut buf = [0u8; 4096];
+        let (size, from) = fallback.recv_from(&mut buf).unwrap();
+        assert_eq!(from.port(), service.socket().local_addr().unwrap().port());
+        let announcement: serde_json::Value = serde_json::from_slice(&buf[..size]).unwrap();
+        assert!(announcement.get("ExampleService").is_some());
+
+        // Heartbeats from the new server keep it current
+        let heartbeat = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#;
+        fallback.send_to(heartbeat.as_bytes(), from).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        service.poll(Some(Duration::from_millis(5)));
+        std::thread::sleep(Duration::from_millis(30));
+        service.poll(Some(Duration::from_millis(5)));
+        assert_eq!(service.server(), fallback_addr);
+        let (size, _) = fallback.recv_from(&mut buf).unwrap();
+        let response: Response = serde_json::from_slice(&buf[..size]).unwrap();
+        assert_eq!(response.request, "1");
+    }
+
+    /// Stand-in HTTP server answering `count` requests, returning each request line and body
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn http_server(count: usize) -> (String, std::thread::JoinHandle<Vec<(String, String)>>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/routes/iotscape", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            (0..count)
+                .map(|_| {
+                    let mut reader = BufReader::new(listener.accept().unwrap().0);
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let mut length = 0;
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        match header.split_once(':') {
+                            Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                                length = value.trim().parse().unwrap();
+                            }
+                            _ if header == "\r\n" => break,
+                            _ => {}
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                        .unwrap();
+                    (request_line.trim_end().to_owned(), String::from_utf8(body).unwrap())
+                })
+                .collect()
+        });
+        (base_url, server)
+    }
+
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    #[test]
+    fn http_transports_post_to_configured_urls() {
+        use iotscape::http::{HttpClient, HttpConfig, HttpTransport, RawHttp, RawTarget};
+        use std::{io::{Read, Write}, time::Duration};
+
+        fn tcp_exchange(target: RawTarget<'_>, request: &[u8]) -> Result<Vec<u8>, String> {
+            let mut stream = std::net::TcpStream::connect((target.host, target.port)).map_err(|e| e.to_string())?;
+            stream.write_all(request).map_err(|e| e.to_string())?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+
+        let clients: [fn(HttpConfig) -> HttpClient; 2] = [
+            HttpClient::new,
+            |config| HttpClient::with_transport(config, RawHttp::new(tcp_exchange)),
+        ];
+        for client in clients {
+            let (base_url, server) = http_server(2);
+            let mut service = mock_service();
+            service.http = Some(client(HttpConfig::new(&base_url).with_timeout(Duration::from_secs(1))));
+            service.max_udp_size = 100;
+
+            let answer = service.announce_http().unwrap();
+            assert_eq!((answer.status, answer.body.as_slice()), (200, &b"ok"[..]));
+
+            // Too large for UDP, so it goes to the response URL
+            let request: Request = serde_json::from_str(
+                r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+            )
+            .unwrap();
+            service.respond(request, Ok(vec!["x".repeat(100).into()])).unwrap();
+            assert!(sent_responses(&service).is_empty());
+
+            let requests = server.join().unwrap();
+            assert!(requests[0].0.starts_with("POST /routes/iotscape/announce HTTP/1."));
+            assert_eq!(requests[0].1, example_definition().announcement("ExampleService"));
+            assert!(requests[1].0.starts_with("POST /routes/iotscape/response HTTP/1."));
+            assert_eq!(serde_json::from_str::<Response>(&requests[1].1).unwrap().request, "1");
+        }
+
+        assert!(RawHttp::new(tcp_exchange).post_json("ftp://localhost/", vec![]).is_err());
+        assert!(mock_service().announce_http().is_err());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn delivery_policy_picks_udp_or_http() {
+        use iotscape::http::{HttpClient, HttpConfig, HttpResponse, HttpTransport};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct RecordingClient(Arc<Mutex<Vec<String>>>);
+
+        impl HttpTransport for RecordingClient {
+            fn post_json(&self, url: &str, _body: Vec<u8>) -> Result<HttpResponse, String> {
+                self.0.lock().unwrap().push(url.to_owned());
+                Ok(HttpResponse { status: 200, body: Vec::new() })
+            }
+        }
+
+        let recorder = RecordingClient::default();
+        let http = || HttpClient::with_transport(HttpConfig::new("http://server/routes/iotscape"), recorder.clone());
+        let request = || {
+            serde_json::from_str::<Request>(r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#)
+                .unwrap()
+        };
+        let large = || Ok(vec!["x".repeat(DEFAULT_MAX_UDP_SIZE).into()]);
+
+        // UDP first, with HTTP for messages too large for UDP
+        let mut service = mock_service();
+        service.http = Some(http());
+        assert_eq!(service.announce().unwrap(), Delivery::Udp);
+        assert_eq!(service.respond(request(), Ok(vec![3.into()])).unwrap(), Delivery::Udp);
+        assert_eq!(service.respond(request(), large()).unwrap(), Delivery::Http);
+        assert_eq!(service.socket().sent.borrow().len(), 2);
+
+        service.delivery = DeliveryPolicy::UdpOnly;
+        assert_eq!(service.respond(request(), large()).unwrap(), Delivery::Udp);
+        assert_eq!(service.socket().sent.borrow().len(), 3);
+
+        service.delivery = DeliveryPolicy::HttpOnly;
+        assert_eq!(service.announce().unwrap(), Delivery::Http);
+        assert_eq!(service.respond(request(), Ok(vec![3.into()])).unwrap(), Delivery::Http);
+        assert_eq!(service.socket().sent.borrow().len(), 3);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "http://server/routes/iotscape/response",
+                "http://server/routes/iotscape/announce",
+                "http://server/routes/iotscape/response",
+            ]
+        );
+
+        service.http = None;
+        assert!(service.announce().is_err());
+
+        // An IPv6 server can't be reached from the IPv4 socket, so UDP fails over to HTTP
+        let mut service = IoTScapeServiceUdp::new("ExampleService", example_definition(), "[::1]:1978".parse().unwrap());
+        assert!(service.announce().is_err());
+        service.http = Some(http());
+        assert_eq!(service.announce().unwrap(), Delivery::Http);
+        assert_eq!(service.respond(request(), Ok(vec![3.into()])).unwrap(), Delivery::Http);
+        assert_eq!(recorder.0.lock().unwrap().len(), 5);
+    }
+
+    #[cfg(all(feature = "http", feature = "security"))]
+    #[test]
+    fn http_responses_are_encrypted() {
+        use iotscape::{
+            http::{HttpClient, HttpConfig, HttpResponse, HttpTransport},
+            security::Security,
+        };
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct RecordingClient(Arc<Mutex<Vec<Vec<u8>>>>);
+
+        impl HttpTransport for RecordingClient {
+            fn post_json(&self, _url: &str, body: Vec<u8>) -> Result<HttpResponse, String> {
+                self.0.lock().unwrap().push(body);
+                Ok(HttpResponse { status: 200, body: Vec::new() })
+            }
+        }
+
+        let recorder = RecordingClient::default();
+        let mut service = mock_service();
+        service.http = Some(HttpClient::with_transport(HttpConfig::new("http://server/routes/iotscape"), recorder.clone()));
+        service.delivery = DeliveryPolicy::HttpOnly;
+        let mut security = Security::default();
+        security.set_key(vec![1]);
+        service.security = Some(security);
+
+        let request: Request = serde_json::from_str(
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+        )
+        .unwrap();
+        assert_eq!(service.respond(request.clone(), Ok(vec!["abc".into()])).unwrap(), Delivery::Http);
+        service.enqueue_response_to_http(request, Ok(vec!["abc".into()])).unwrap();
+        for body in recorder.0.lock().unwrap().iter() {
+            let sent: Response = serde_json::from_slice(body).unwrap();
+            assert_eq!(sent.response, Some(vec!["bcd".into()]));
+        }
+    }
+
+    #[test]
+    fn failed_heartbeat_replies_are_logged() {
+        let mut service = mock_service();
+        service.delivery = DeliveryPolicy::HttpOnly;
+        push_request(&service, "10.0.0.1:1978", r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#);
+        push_request(&service, "10.0.0.1:1978", r#"{"id":"2","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#);
+        service.poll(None);
+        assert!(service.socket().sent.borrow().is_empty());
+        assert_eq!(service.rx_queue.pop_front().unwrap().id, "2");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn failed_heartbeat_replies_are_logged_async() {
+        use std::time::Duration;
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut service: IoTScapeServiceAsync =
+            IoTScapeServiceAsync::new("ExampleService", example_definition(), server.local_addr().unwrap()).await;
+        service.delivery = DeliveryPolicy::HttpOnly;
+        let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+        for request in [
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#,
+            r#"{"id":"2","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+        ] {
+            server.send_to(request.as_bytes(), device).await.unwrap();
+        }
+
+        let request = tokio::time::timeout(Duration::from_secs(1), service.next_request()).await.unwrap().unwrap();
+        assert_eq!(request.id, "2");
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn websocket_service_talks_over_outbound_connection() {
+        use iotscape::websocket::{WsService, WsSocket};
+        use std::time::Duration;
+        use tungstenite::Message;
+
+        // Stand-in server relaying datagrams as messages, which drops the first connection
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/iotscape", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            received.push(ws.read().unwrap().into_text().unwrap().to_string());
+            let request = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+            ws.send(Message::text(request)).unwrap();
+            received.push(ws.read().unwrap().into_text().unwrap().to_string());
+            ws.close(None).unwrap();
+            while ws.read().is_ok() {}
+            drop(ws);
+
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            received.push(ws.read().unwrap().into_text().unwrap().to_string());
+            received
+        });
+
+        let mut service = WsService::connect("ExampleService", example_definition(), &url).unwrap();
+        assert_eq!(service.server(), service.socket().server());
+        // Messages of any size go over the connection rather than HTTP
+        #[cfg(feature = "http")]
+        {
+            use iotscape::http::{HttpClient, HttpConfig, HttpResponse, HttpTransport};
+
+            struct NoHttp;
+
+            impl HttpTransport for NoHttp {
+                fn post_json(&self, url: &str, _body: Vec<u8>) -> Result<HttpResponse, String> {
+                    panic!("Sent over HTTP to {}", url);
+                }
+            }
+
+            service.http = Some(HttpClient::with_transport(HttpConfig::new("http://server/routes/iotscape"), NoHttp));
+            service.max_udp_size = 10;
+        }
+        assert_eq!(service.announce().unwrap(), Delivery::WebSocket);
+
+        let mut request = None;
+        for _ in 0..100 {
+            service.poll(Some(Duration::from_millis(10)));
+            request = service.rx_queue.pop_front();
+            if request.is_some() {
+                break;
+            }
+        }
+        let request = request.expect("No request over the WebSocket");
+        assert_eq!((request.function.as_str(), request.params.len()), ("add", 2));
+        service.respond(request, Ok(vec![3.into()])).unwrap();
+
+        // The closed connection is opened again for the next send
+        for _ in 0..100 {
+            if !service.socket().is_connected() {
+                break;
+            }
+            service.poll(Some(Duration::from_millis(10)));
+        }
+        assert!(!service.socket().is_connected());
+        service.announce().unwrap();
+        assert!(service.socket().is_connected());
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0], example_definition().announcement("ExampleService"));
+        assert_eq!(serde_json::from_str::<Response>(&received[1]).unwrap().response, Some(vec![3.into()]));
+        assert_eq!(received[2], received[0]);
+        assert!(WsSocket::connect("ws://127.0.0.1:1/iotscape").is_err());
+    }
+
+    #[cfg(all(feature = "websocket", feature = "tokio"))]
+    #[tokio::test]
+    async fn websocket_service_async() {
+        use iotscape::{config::IoTScapeConfig, websocket::WsServiceAsync};
+        use std::time::Duration;
+        use tungstenite::Message;
+
+        // Stand-in relay sending a request once the service announces
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/iotscape", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            let announcement = ws.read().unwrap().into_text().unwrap().to_string();
+            let request = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+            ws.send(Message::text(request)).unwrap();
+            let response = ws.read().unwrap().into_text().unwrap().to_string();
+            (announcement, response)
+        });
+
+        let config = IoTScapeConfig::local().with_websocket_url(&url);
+        let service = WsServiceAsync::connect_from_config("ExampleService", example_definition(), &config).await.unwrap();
+        assert_eq!(service.server(), service.socket().unwrap().server());
+        assert_eq!(service.announce().await.unwrap(), Delivery::WebSocket);
+
+        let request = tokio::time::timeout(Duration::from_secs(5), service.next_request()).await.unwrap().unwrap();
+        assert_eq!((request.function.as_str(), request.params.len()), ("add", 2));
+        assert_eq!(service.respond(request, Ok(vec![3.into()])).await.unwrap(), Delivery::WebSocket);
+
+        let (announcement, response) = server.join().unwrap();
+        assert_eq!(announcement, example_definition().announcement("ExampleService"));
+        assert_eq!(serde_json::from_str::<Response>(&response).unwrap().response, Some(vec![3.into()]));
+        assert!(WsServiceAsync::connect_from_config("ExampleService", example_definition(), &IoTScapeConfig::local()).await.is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn next_request_waits_for_datagrams() {
+        use std::{sync::Arc, time::Duration};
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service: Arc<IoTScapeServiceAsync> = Arc::new(
+            IoTScapeServiceAsync::new("ExampleService", example_definition(), server.local_addr().unwrap()).await,
+        );
+        let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+
+        let waiting = tokio::spawn({
+            let service = service.clone();
+            async move { service.next_request().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for request in [
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#,
+            r#"{"id":"2","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+        ] {
+            server.send_to(request.as_bytes(), device).await.unwrap();
+        }
+
+        let request = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(request.id, "2");
+        let mut buf = [0u8; 65_535];
+        let (size, _) = server.recv_from(&mut buf).await.unwrap();
+        let heartbeat: Response = serde_json::from_slice(&buf[..size]).unwrap();
+        assert_eq!(heartbeat.request, "1");
+
+        // Shutting down wakes a waiting call
+        let waiting = tokio::spawn({
+            let service = service.clone();
+            async move { service.next_request().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        service.shutdown(Duration::from_millis(100), false).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().is_none());
+        assert!(service.socket().is_none());
+        assert!(service.announce().await.is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn slow_handlers_time_out() {
+        use std::{sync::Arc, time::Duration};
+
+        let mut definition = example_definition();
+        definition.methods.get_mut("add").unwrap().timeout = Some(Duration::from_millis(50));
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service: IoTScapeServiceAsync =
+            IoTScapeServiceAsync::new("ExampleService", definition, server.local_addr().unwrap()).await;
+        let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+        let request = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+        server.send_to(request.as_bytes(), device).await.unwrap();
+        let request = tokio::time::timeout(Duration::from_secs(1), service.next_request()).await.unwrap().unwrap();
+
+        // The handler never finishes, and holds a reference until it is dropped
+        let alive = Arc::new(());
+        let handler = {
+            let alive = alive.clone();
+            async move {
+                let _alive = alive;
+                futures::future::pending::<Result<Vec<serde_json::Value>, String>>().await
+            }
+        };
+        let delivery = tokio::time::timeout(Duration::from_secs(1), service.run_handler(request, handler)).await.unwrap();
+        assert_eq!(delivery.unwrap(), Some(Delivery::Udp));
+        assert_eq!(Arc::strong_count(&alive), 1);
+        assert_eq!(service.stats.timed_out.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let mut buf = [0u8; 65_535];
+        let response = loop {
+            let (size, _) = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf)).await.unwrap().unwrap();
+            if let Ok(response) = serde_json::from_slice::<Response>(&buf[..size]) {
+                break response;
+            }
+        };
+        assert_eq!(response.request, "1");
+        assert_eq!(response.error.as_deref(), Some(TIMEOUT_ERROR));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn request_stream_and_response_sink() {
+        use futures::{SinkExt, StreamExt};
+        use std::{sync::Arc, time::Duration};
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service: Arc<IoTScapeServiceAsync> = Arc::new(
+            IoTScapeServiceAsync::new("ExampleService", example_definition(), server.local_addr().unwrap()).await,
+        );
+        let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+
+        tokio::spawn({
+            let service = service.clone();
+            async move {
+                let mut responses = service.responses();
+                let mut requests = service.requests();
+                while let Some(request) = requests.next().await {
+                    let sum = request.params.iter().filter_map(|p| p.as_f64()).sum::<f64>();
+                    responses.send(service.response_to(&request, Ok(vec![sum.into()]))).await.unwrap();
+                }
+            }
+        });
+
+        for request in [
+            r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+            r#"{"id":"2","service":"ExampleService","device":"rs1","function":"add","params":[3,4]}"#,
+        ] {
+            server.send_to(request.as_bytes(), device).await.unwrap();
+        }
+
+        let mut buf = [0u8; 65_535];
+        for (id, sum) in [("1", 3.0), ("2", 7.0)] {
+            let (size, _) = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf)).await.unwrap().unwrap();
+            let response: Response = serde_json::from_slice(&buf[..size]).unwrap();
+            assert_eq!(response.request, id);
+            assert_eq!(response.response, Some(vec![sum.into()]));
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn polled_request_and_response_queues() {
+        use std::time::Duration;
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service: IoTScapeServiceAsync =
+            IoTScapeServiceAsync::new("ExampleService", example_definition(), server.local_addr().unwrap()).await;
+        let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().local_addr().unwrap().port()));
+        let request = r#"{"id":"1","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#;
+        server.send_to(request.as_bytes(), device).await.unwrap();
+
+        let mut request = None;
+        for _ in 0..100 {
+            service.poll().await;
+            request = service.pop_request();
+            if request.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let request = request.expect("No request queued");
+        assert!(service.pop_request().is_none());
+
+        service.queue_response(service.response_to(&request, Ok(vec![3.into()])));
+        service.poll().await;
+        let mut buf = [0u8; 65_535];
+        let (size, _) = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf)).await.unwrap().unwrap();
+        let response: Response = serde_json::from_slice(&buf[..size]).unwrap();
+        assert_eq!((response.request.as_str(), response.response), ("1", Some(vec![3.into()])));
+    }
+
+    #[cfg(all(feature = "smol", feature = "http_announce"))]
+    #[test]
+    fn async_service_without_tokio() {
+        use async_io::{Async, Timer};
+        use futures::future::{select, BoxFuture, Either, FutureExt};
+        use iotscape::http::{HttpClientAsync, HttpConfig, HttpResponse, HttpTransportAsync};
+        use std::{net::UdpSocket, pin::pin, sync::{Arc, Mutex}, time::Duration};
+
+        type Posts = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+        struct RecordingClient(Posts);
+
+        impl HttpTransportAsync for RecordingClient {
+            fn post_json<'a>(&'a self, url: &'a str, body: Vec<u8>) -> BoxFuture<'a, Result<HttpResponse, std::io::Error>> {
+                self.0.lock().unwrap().push((url.to_owned(), body));
+                async { Ok(HttpResponse { status: 200, body: vec![] }) }.boxed()
+            }
+        }
+
+        async_io::block_on(async {
+            let server = Async::<UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let mut service: IoTScapeServiceAsyncSmol =
+                IoTScapeServiceAsync::new("ExampleService", example_definition(), server.get_ref().local_addr().unwrap()).await;
+            let posts = Arc::new(Mutex::new(Vec::new()));
+            service.http = Some(HttpClientAsync::with_transport(
+                HttpConfig::new("http://localhost/routes/iotscape"),
+                RecordingClient(posts.clone()),
+            ));
+            let device = std::net::SocketAddr::from(([127, 0, 0, 1], service.socket().unwrap().get_ref().local_addr().unwrap().port()));
+
+            for request in [
+                r#"{"id":"1","service":"ExampleService","device":"rs1","function":"heartbeat","params":[]}"#,
+                r#"{"id":"2","service":"ExampleService","device":"rs1","function":"add","params":[1,2]}"#,
+            ] {
+                server.send_to(request.as_bytes(), device).await.unwrap();
+            }
+
+            let request = match select(pin!(service.next_request()), Timer::after(Duration::from_secs(1))).await {
+                Either::Left((request, _)) => request.unwrap(),
+                Either::Right(_) => panic!("request not received"),
+            };
+            assert_eq!(request.id, "2");
+            let mut buf = [0u8; 65_535];
+            let (size, _) = server.recv_from(&mut buf).await.unwrap();
+            let heartbeat: Response = serde_json::from_slice(&buf[..size]).unwrap();
+            assert_eq!(heartbeat.request, "1");
+
+            let answer = service.announce_http().await.unwrap();
+            assert!(answer.is_success());
+            let (url, body) = posts.lock().unwrap().remove(0);
+            assert_eq!(url, "http://localhost/routes/iotscape/announce");
+            assert!(String::from_utf8(body).unwrap().contains("ExampleService"));
+
+            service.shutdown(Duration::from_millis(100), false).await.unwrap();
+            assert!(service.next_request().await.is_none());
+        });
+    }
 }